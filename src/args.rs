@@ -1,32 +1,144 @@
 use std::{
+    collections::HashMap,
     ffi::OsString,
+    io::{IsTerminal, Read},
     path::{Path, PathBuf},
 };
 
 use clap::{
-    Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Parser, Subcommand,
+    parser::ValueSource, Arg, ArgAction, ArgMatches, Args, Command, CommandFactory, FromArgMatches,
+    Parser, Subcommand,
 };
 use error_stack::{Report, ResultExt};
+use itertools::Itertools;
 
 use crate::{
     context::OverflowKeep,
     error::Error,
     image::ImageData,
+    interactive::prompt_for_options,
     model::OutputFormat,
     template::{OptionType, PromptOption, PromptTemplate},
+    vars::TemplateVars,
 };
 
 #[derive(Parser, Debug)]
 pub struct Cli {
     #[command(subcommand)]
-    command: MainCommand,
+    pub command: MainCommand,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum MainCommand {
-    Run(GlobalRunArgs),
+    Run(Box<GlobalRunArgs>),
+    /// Run the tests defined in templates
+    Test(TestArgs),
+    /// Compare the prompts rendered for each template's tests against saved snapshots
+    Snapshot(SnapshotArgs),
+    /// Check all the templates for errors and unused or undeclared variables
+    Check,
+    /// Show a template's models, the host chosen for each, and its options
+    Show(ShowArgs),
+    /// List the models available from each host
+    Models(ModelsArgs),
+    /// Diagnose problems with the configuration, hosts, and environment
+    Doctor(DoctorArgs),
+    /// Show the configuration and where it comes from
+    Config(ConfigArgs),
     // List
-    // Show
+}
+
+#[derive(Parser, Debug)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print each configuration value, along with the file or environment variable that set it
+    Show(ConfigShowArgs),
+    /// List the configuration files that PromptBox looks for, in the order they are merged
+    Paths,
+}
+
+/// Selects a profile from the configuration, for the commands that use its model options.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ProfileArgs {
+    /// Use the model options from this profile in the configuration
+    #[arg(long, env = "PROMPTBOX_PROFILE")]
+    pub profile: Option<String>,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct ConfigShowArgs {
+    /// Also apply the model options from this template
+    #[arg(long)]
+    pub template: Option<String>,
+
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct DoctorArgs {
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct ModelsArgs {
+    /// Only list models from this host. Can be given more than once.
+    #[arg(long)]
+    pub host: Vec<String>,
+
+    /// Print the models as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Fetch the model lists from the hosts instead of using cached lists
+    #[arg(long)]
+    pub refresh: bool,
+
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct ShowArgs {
+    /// The template to show
+    pub template: String,
+
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct TestArgs {
+    /// The templates to test. If omitted, all templates are tested.
+    pub templates: Vec<String>,
+
+    /// Only render the prompts, and skip tests that check the model output
+    #[arg(long)]
+    pub skip_output: bool,
+
+    /// How many model requests to run at once
+    #[arg(long, short = 'j', default_value_t = 4)]
+    pub jobs: usize,
+
+    /// Write a JUnit XML report to this file
+    #[arg(long)]
+    pub junit: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Default)]
+pub struct SnapshotArgs {
+    /// The templates to check. If omitted, all templates are checked.
+    pub templates: Vec<String>,
+
+    /// Write the rendered prompts to the snapshot files, accepting any changes
+    #[arg(long)]
+    pub update: bool,
 }
 
 #[derive(Parser, Debug, Default)]
@@ -34,17 +146,13 @@ pub struct GlobalRunArgs {
     /// The template to run
     pub template: String,
 
-    /// LM Studio host, if different from the default
-    #[arg(long, env = "LM_STUDIO_HOST")]
-    pub lm_studio_host: Option<String>,
-
-    /// Ollama host, if different from the default
-    #[arg(long, env = "OLLAMA_HOST")]
-    pub ollama_host: Option<String>,
+    /// Override the endpoint of a host, as NAME=URL. This can be given more than once.
+    #[arg(long, value_name = "NAME=URL", value_parser = parse_host_endpoint)]
+    pub host_endpoint: Vec<(String, String)>,
 
-    /// OpenAI Key
-    #[arg(long, env = "OPENAI_KEY")]
-    pub openai_key: Option<String>,
+    /// Use this API key for the model's host, instead of the configured key
+    #[arg(long)]
+    pub api_key: Option<String>,
 
     /// Override the model used by the template
     #[arg(long, short = 'm', env = "MODEL")]
@@ -54,6 +162,9 @@ pub struct GlobalRunArgs {
     #[arg(long, env = "MODEL_HOST")]
     pub model_host: Option<String>,
 
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+
     /// Override the temperature value passed to the model
     #[arg(long, short = 't')]
     pub temperature: Option<f32>,
@@ -66,6 +177,11 @@ pub struct GlobalRunArgs {
     #[arg(long = "post")]
     pub append: Option<String>,
 
+    /// Write the extra content for the prompt in your editor. Any extra content from the
+    /// command line or stdin is placed in the editor to start.
+    #[arg(long)]
+    pub edit: bool,
+
     /// Print the generated prompt
     #[arg(long)]
     pub print_prompt: bool,
@@ -96,6 +212,28 @@ pub struct GlobalRunArgs {
     #[arg(long)]
     pub reserve_output_context: Option<usize>,
 
+    /// Read values for the template's options from a JSON or TOML file.
+    /// Options given on the command line take precedence.
+    #[arg(long)]
+    pub vars: Option<PathBuf>,
+
+    /// Read values for the template's options from a JSON object on stdin.
+    #[arg(long, conflicts_with = "vars")]
+    pub vars_stdin: bool,
+
+    /// Use the contents of stdin as the value of this option, instead of as extra content.
+    #[arg(long, conflicts_with = "vars_stdin")]
+    pub stdin_to: Option<String>,
+
+    /// Don't read anything from stdin, and don't prompt for missing options.
+    #[arg(long, conflicts_with_all = ["vars_stdin", "stdin_to", "edit"])]
+    pub no_stdin: bool,
+
+    /// Set when stdin was read as the value of an option, so it won't also be read as extra
+    /// content.
+    #[arg(skip)]
+    pub stdin_consumed: bool,
+
     /// Extra strings to add to the end of the prompt.
     pub extra_prompt: Vec<String>,
 }
@@ -108,6 +246,15 @@ pub enum FoundCommand {
     Other(Cli),
 }
 
+fn parse_host_endpoint(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+            Ok((name.to_string(), url.to_string()))
+        }
+        _ => Err(format!("Expected NAME=URL, found {value}")),
+    }
+}
+
 pub fn parse_main_args(cmdline: Vec<OsString>) -> Result<FoundCommand, clap::Error> {
     let first_arg = cmdline
         .get(1)
@@ -133,53 +280,123 @@ pub fn parse_main_args(cmdline: Vec<OsString>) -> Result<FoundCommand, clap::Err
     }
 }
 
+/// Build the `run` subcommand, merging the global run options with the options from the template.
+/// `presets` supplies values for template options that come from somewhere other than the command
+/// line. These are applied as defaults so that explicit arguments still take precedence.
+/// `stdin_option` is not required, since its value may come from stdin.
+fn build_run_command(
+    template: &PromptTemplate,
+    presets: &HashMap<String, Vec<String>>,
+    stdin_option: Option<&str>,
+    require_options: bool,
+) -> Command {
+    let args = template.options.iter().map(|(name, option)| {
+        let action = if option.array {
+            ArgAction::Append
+        } else {
+            ArgAction::Set
+        };
+
+        let preset = presets.get(name);
+
+        let arg = Arg::new(name.to_string())
+            .long(name.to_string())
+            .required(
+                require_options
+                    && option.is_required()
+                    && preset.is_none()
+                    && stdin_option != Some(name.as_str()),
+            )
+            .help(&option.description)
+            .action(action);
+
+        // Flags take an optional value, so that `--flag=false` can turn off a flag set by a preset.
+        let arg = if option.option_type == OptionType::Bool && !option.array {
+            arg.num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("true")
+                .default_value("false")
+        } else {
+            arg
+        };
+
+        let arg = match preset {
+            Some(values) => arg.default_values(values.iter().cloned()),
+            None => arg,
+        };
+
+        match option.option_type {
+            OptionType::String => arg.value_parser(clap::builder::NonEmptyStringValueParser::new()),
+            OptionType::Number => arg.value_parser(clap::value_parser!(f32)),
+            OptionType::Integer => arg.value_parser(clap::value_parser!(i64)),
+            OptionType::Bool => arg.value_parser(clap::value_parser!(bool)),
+            OptionType::File
+            | OptionType::Image
+            | OptionType::Json
+            | OptionType::Toml
+            | OptionType::Yaml
+            | OptionType::Csv => arg.value_parser(clap::value_parser!(PathBuf)),
+        }
+    });
+
+    let run_command = Command::new("run")
+        .args(GlobalRunArgs::command().get_arguments())
+        .args(args);
+
+    Command::new("promptbox").subcommand(run_command)
+}
+
+/// Parse the arguments for a template. `vars`, if provided, supplies option values in the same way
+/// as the `--vars` argument.
 pub fn parse_template_args(
     cmdline: Vec<OsString>,
     base_dir: &Path,
     template: &PromptTemplate,
+    vars: Option<TemplateVars>,
 ) -> Result<(GlobalRunArgs, serde_json::Value, Vec<ImageData>), Report<Error>> {
-    let args = template
-        .options
-        .iter()
-        .map(|(name, option)| {
-            let action = match (option.array, option.option_type) {
-                (true, _) => ArgAction::Append,
-                (false, OptionType::Bool) => ArgAction::SetTrue,
-                (false, _) => ArgAction::Set,
-            };
+    // Do an initial parse without requiring the template options, to see if any values should be
+    // read from elsewhere.
+    let initial_parsed = build_run_command(template, &HashMap::new(), None, false)
+        .try_get_matches_from(cmdline.clone())
+        .map_err(Error::from)?;
+    let initial_run_args = initial_parsed
+        .subcommand_matches("run")
+        .ok_or(Error::ArgParseFailure)?;
 
-            let arg = Arg::new(name.to_string())
-                .long(name.to_string())
-                .required(
-                    option.option_type != OptionType::Bool
-                        && option.default.is_none()
-                        && !option.optional,
-                )
-                .help(&option.description)
-                .action(action);
-
-            let arg = match option.option_type {
-                OptionType::String => {
-                    arg.value_parser(clap::builder::NonEmptyStringValueParser::new())
-                }
-                OptionType::Number => arg.value_parser(clap::value_parser!(f32)),
-                OptionType::Integer => arg.value_parser(clap::value_parser!(i64)),
-                OptionType::Bool => arg.value_parser(clap::value_parser!(bool)),
-                OptionType::File => arg.value_parser(clap::value_parser!(PathBuf)),
-                OptionType::Image => arg.value_parser(clap::value_parser!(PathBuf)),
-            };
+    let vars = match vars {
+        Some(vars) => Some(vars),
+        None => TemplateVars::from_args(initial_run_args, base_dir)?,
+    };
+    let use_stdin = !initial_run_args.get_flag("no_stdin");
+    let stdin_option = stdin_option(initial_run_args, template)?.filter(|_| {
+        use_stdin && !initial_run_args.get_flag("vars_stdin") && !std::io::stdin().is_terminal()
+    });
 
-            Ok(arg)
-        })
-        .collect::<Result<Vec<_>, Report<Error>>>()?;
+    let mut presets = match vars {
+        Some(vars) => vars.preset_values(template)?,
+        None => HashMap::new(),
+    };
 
-    // Merge together the args from the global run options and from the template.
-    let run_command = Command::new("run")
-        .args(GlobalRunArgs::command().get_arguments())
-        .args(args);
+    // When running interactively, ask for any required options that are still missing instead of
+    // failing.
+    if use_stdin && std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        let missing = template
+            .options
+            .iter()
+            .filter(|(name, option)| {
+                option.is_required()
+                    && !presets.contains_key(*name)
+                    && !initial_run_args.contains_id(name)
+            })
+            .sorted_by_key(|(name, _)| *name)
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            presets.extend(prompt_for_options(base_dir, &missing)?);
+        }
+    }
 
-    let main_parsed = Command::new("promptbox")
-        .subcommand(run_command)
+    let main_parsed = build_run_command(template, &presets, stdin_option.as_deref(), true)
         .try_get_matches_from(cmdline)
         .map_err(Error::from)?;
 
@@ -190,7 +407,35 @@ pub fn parse_template_args(
 
     let mut context = serde_json::json!({});
     let mut images = vec![];
+    let mut stdin_consumed = false;
     for (name, option) in &template.options {
+        // Read the value from stdin, unless it was also given explicitly on the command line.
+        if stdin_option.as_ref() == Some(name)
+            && parsed.value_source(name) != Some(ValueSource::CommandLine)
+        {
+            let mut contents = vec![];
+            std::io::stdin()
+                .read_to_end(&mut contents)
+                .change_context(Error::Io)
+                .attach_printable("Reading stdin")?;
+            stdin_consumed = true;
+
+            if contents.is_empty() {
+                if option.is_required() {
+                    return Err(Report::new(Error::ArgParseFailure))
+                        .attach_printable(format!("Option {name} requires a value from stdin"));
+                }
+            } else {
+                match value_from_stdin(option, contents)
+                    .attach_printable_lazy(|| format!("Reading option {name} from stdin"))?
+                {
+                    StdinValue::Context(value) => context[name] = value,
+                    StdinValue::Image(image) => images.push(image),
+                }
+                continue;
+            }
+        }
+
         match option.option_type {
             OptionType::Bool => add_val_to_context::<bool>(&mut context, &mut parsed, name, option),
             OptionType::Number => {
@@ -240,15 +485,113 @@ pub fn parse_template_args(
                     context[name] = val.unwrap_or(serde_json::Value::Null);
                 }
             }
+            OptionType::Json | OptionType::Toml | OptionType::Yaml | OptionType::Csv => {
+                if option.array {
+                    let vals = parsed
+                        .remove_many::<PathBuf>(name)
+                        .unwrap_or_default()
+                        .map(|path| create_data_object(base_dir, &path, option.option_type))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    context[name] = serde_json::Value::Array(vals);
+                } else {
+                    let val = parsed
+                        .remove_one::<PathBuf>(name)
+                        .map(|path| create_data_object(base_dir, &path, option.option_type))
+                        .transpose()?;
+                    context[name] = val.unwrap_or(serde_json::Value::Null);
+                }
+            }
         }
     }
 
-    let global_args =
+    let mut global_args =
         GlobalRunArgs::from_arg_matches_mut(&mut parsed).change_context(Error::ArgParseFailure)?;
+    global_args.stdin_consumed = stdin_consumed;
 
     Ok((global_args, context, images))
 }
 
+/// Find the option, if any, which should receive the contents of stdin.
+fn stdin_option(
+    args: &ArgMatches,
+    template: &PromptTemplate,
+) -> Result<Option<String>, Report<Error>> {
+    if let Some(name) = args.get_one::<String>("stdin_to") {
+        if !template.options.contains_key(name) {
+            return Err(Report::new(Error::ArgParseFailure))
+                .attach_printable(format!("--stdin-to: The template has no option {name}"));
+        }
+
+        return Ok(Some(name.clone()));
+    }
+
+    let mut stdin_options = template
+        .options
+        .iter()
+        .filter(|(_, option)| option.stdin)
+        .map(|(name, _)| name);
+    match (stdin_options.next(), stdin_options.next()) {
+        (Some(_), Some(_)) => Err(Report::new(Error::ArgParseFailure))
+            .attach_printable("Only one option can set `stdin = true`"),
+        (name, _) => Ok(name.cloned()),
+    }
+}
+
+enum StdinValue {
+    Context(serde_json::Value),
+    Image(ImageData),
+}
+
+/// Convert the contents of stdin into a value for an option.
+fn value_from_stdin(option: &PromptOption, contents: Vec<u8>) -> Result<StdinValue, Report<Error>> {
+    if option.option_type == OptionType::Image {
+        return ImageData::from_bytes(contents).map(StdinValue::Image);
+    }
+
+    let text = String::from_utf8(contents)
+        .change_context(Error::ArgParseFailure)
+        .attach_printable("stdin is not valid UTF-8")?;
+
+    let value = match option.option_type {
+        OptionType::String => serde_json::Value::from(text),
+        OptionType::Integer => text
+            .trim()
+            .parse::<i64>()
+            .change_context(Error::ArgParseFailure)?
+            .into(),
+        OptionType::Number => text
+            .trim()
+            .parse::<f32>()
+            .change_context(Error::ArgParseFailure)?
+            .into(),
+        OptionType::Bool => text
+            .trim()
+            .parse::<bool>()
+            .change_context(Error::ArgParseFailure)?
+            .into(),
+        OptionType::File => serde_json::json!({
+            "filename": "stdin",
+            "path": "stdin",
+            "contents": text,
+        }),
+        OptionType::Json | OptionType::Toml | OptionType::Yaml | OptionType::Csv => {
+            parse_data(option.option_type, &text).map_err(|(e, line)| match line {
+                Some(line) => e.attach_printable(format!("stdin:{line}")),
+                None => e.attach_printable("stdin"),
+            })?
+        }
+        OptionType::Image => unreachable!(),
+    };
+
+    let value = if option.array {
+        serde_json::Value::Array(vec![value])
+    } else {
+        value
+    };
+
+    Ok(StdinValue::Context(value))
+}
+
 fn read_image(base_dir: &Path, path: &Path) -> Result<ImageData, Report<Error>> {
     let path = base_dir
         .join(path)
@@ -274,6 +617,76 @@ fn create_file_object(
     Ok(obj)
 }
 
+/// Read a structured data file and parse it into a JSON value for the template context.
+fn create_data_object(
+    base_dir: &Path,
+    path: &Path,
+    option_type: OptionType,
+) -> Result<serde_json::Value, Report<Error>> {
+    let contents = std::fs::read_to_string(base_dir.join(path))
+        .change_context(Error::ArgParseFailure)
+        .attach_printable_lazy(|| format!("Could not read file: {}", path.display()))?;
+
+    parse_data(option_type, &contents).map_err(|(e, line)| match line {
+        Some(line) => e.attach_printable(format!("{}:{line}", path.display())),
+        None => e.attach_printable(path.display().to_string()),
+    })
+}
+
+/// Parse structured data of the given type. On failure, the line where the error occurred is
+/// returned alongside the error, if it is known.
+fn parse_data(
+    option_type: OptionType,
+    contents: &str,
+) -> Result<serde_json::Value, (Report<Error>, Option<usize>)> {
+    fn failure(
+        e: impl std::error::Error + Send + Sync + 'static,
+        line: Option<usize>,
+    ) -> (Report<Error>, Option<usize>) {
+        (Report::new(e).change_context(Error::ArgParseFailure), line)
+    }
+
+    match option_type {
+        OptionType::Json => serde_json::from_str(contents).map_err(|e| {
+            let line = e.line();
+            failure(e, Some(line))
+        }),
+        OptionType::Toml => toml::from_str(contents).map_err(|e| {
+            let line = e
+                .span()
+                .map(|span| contents[..span.start].matches('\n').count() + 1);
+            failure(e, line)
+        }),
+        OptionType::Yaml => serde_yaml::from_str(contents).map_err(|e| {
+            let line = e.location().map(|l| l.line());
+            failure(e, line)
+        }),
+        OptionType::Csv => {
+            let csv_failure = |e: csv::Error| {
+                let line = e.position().map(|p| p.line() as usize);
+                failure(e, line)
+            };
+
+            let mut reader = csv::Reader::from_reader(contents.as_bytes());
+            let headers = reader.headers().map_err(csv_failure)?.clone();
+            reader
+                .records()
+                .map(|record| {
+                    let record = record.map_err(csv_failure)?;
+                    let row = headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(header, value)| (header.to_string(), value.into()))
+                        .collect::<serde_json::Map<_, _>>();
+                    Ok(serde_json::Value::Object(row))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(serde_json::Value::Array)
+        }
+        _ => unreachable!("parse_data called with non-data option type {option_type:?}"),
+    }
+}
+
 fn add_val_to_context<T: Clone + Send + Sync + Into<serde_json::Value> + 'static>(
     context: &mut serde_json::Value,
     args: &mut ArgMatches,
@@ -299,3 +712,69 @@ fn add_val_to_context<T: Clone + Send + Sync + Into<serde_json::Value> + 'static
 
     context[name] = val;
 }
+
+#[cfg(test)]
+mod test {
+    use super::{parse_data, value_from_stdin, StdinValue};
+    use crate::template::{OptionType, PromptOption};
+
+    fn stdin_value(option: &str, contents: &str) -> serde_json::Value {
+        let option: PromptOption = toml::from_str(option).expect("parsing option");
+        match value_from_stdin(&option, contents.as_bytes().to_vec()).expect("reading value") {
+            StdinValue::Context(value) => value,
+            StdinValue::Image(_) => panic!("expected a context value"),
+        }
+    }
+
+    #[test]
+    fn stdin_string() {
+        assert_eq!(
+            stdin_value(r#"type = "string""#, "some text\n"),
+            serde_json::json!("some text\n")
+        );
+    }
+
+    #[test]
+    fn stdin_number() {
+        assert_eq!(stdin_value(r#"type = "int""#, "5\n"), serde_json::json!(5));
+    }
+
+    #[test]
+    fn stdin_file() {
+        assert_eq!(
+            stdin_value(r#"type = "file""#, "contents"),
+            serde_json::json!({ "filename": "stdin", "path": "stdin", "contents": "contents" })
+        );
+    }
+
+    #[test]
+    fn stdin_json_array() {
+        assert_eq!(
+            stdin_value(
+                r#"
+                type = "json"
+                array = true
+                "#,
+                r#"{ "a": 1 }"#
+            ),
+            serde_json::json!([{ "a": 1 }])
+        );
+    }
+
+    #[test]
+    fn stdin_bad_value() {
+        let option: PromptOption = toml::from_str(r#"type = "int""#).unwrap();
+        let result = value_from_stdin(&option, b"abc".to_vec());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn toml_error_line() {
+        // The duplicate key starts at column 0 of the third line.
+        let (_, line) = parse_data(OptionType::Toml, "a = 1\nb = 2\na = 3\n").unwrap_err();
+        assert_eq!(line, Some(3));
+
+        let (_, line) = parse_data(OptionType::Toml, "a = 1\nb = \n").unwrap_err();
+        assert_eq!(line, Some(2));
+    }
+}
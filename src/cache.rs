@@ -1,4 +1,7 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use error_stack::{Report, ResultExt};
 use etcetera::BaseStrategy;
@@ -23,6 +26,15 @@ impl Cache {
         Ok(Self { dir })
     }
 
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    #[cfg(test)]
+    pub fn with_dir(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
     /// Read a file from the cache if it's not older than `max_stale`.
     pub fn read_cache<T: DeserializeOwned>(
         &self,
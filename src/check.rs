@@ -0,0 +1,494 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::PathBuf,
+};
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+use tera::ast::{Expr, ExprVal, Node};
+
+use crate::{
+    config::Config,
+    error::error_message,
+    error::Error,
+    model::ModelOptions,
+    template::{OptionType, ParsedTemplate},
+};
+
+/// Variables which Tera provides itself.
+const BUILTIN_VARIABLES: &[&str] = &["loop", "__tera_context"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// The file or other source of the problem
+    pub location: String,
+    pub message: String,
+}
+
+/// Check the configuration and every template in the template directories.
+pub fn check_templates(base_dir: PathBuf) -> Result<Vec<Problem>, Report<Error>> {
+    let config = Config::from_directory(base_dir)?;
+    let mut problems = check_model_options(&config.model, "configuration");
+
+    // Only report problems that a profile adds to the configuration's.
+    let config_messages = problems
+        .iter()
+        .map(|p| p.message.clone())
+        .collect::<HashSet<_>>();
+    for name in config.profiles.keys().sorted() {
+        let options = config.profile_model_options(name)?;
+        problems.extend(
+            check_model_options(&options, &format!("profile {name}"))
+                .into_iter()
+                .filter(|p| !config_messages.contains(&p.message)),
+        );
+    }
+
+    let mut templates = vec![];
+    for (name, path) in config.template_files() {
+        match ParsedTemplate::from_file(&name, &path) {
+            Ok(Some(template)) => templates.push(template),
+            Ok(None) => {}
+            Err(e) => problems.push(Problem {
+                location: path.display().to_string(),
+                message: error_message(&e),
+            }),
+        }
+    }
+
+    // trim_args in the configuration apply to all templates, so only report names that no
+    // template uses.
+    let all_options = templates
+        .iter()
+        .flat_map(|t| t.input.options.keys())
+        .collect::<HashSet<_>>();
+    for arg in &config.model.context.trim_args {
+        if arg != "extra" && !all_options.contains(arg) {
+            problems.push(Problem {
+                location: "configuration".to_string(),
+                message: format!("trim_args contains {arg}, which no template has as an option"),
+            });
+        }
+    }
+
+    for template in &templates {
+        problems.extend(check_template(&config, template));
+    }
+
+    Ok(problems)
+}
+
+/// Check that the hosts referenced by model options exist, and that aliases resolve.
+fn check_model_options(options: &ModelOptions, location: &str) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut problem = |message: String| {
+        problems.push(Problem {
+            location: location.to_string(),
+            message,
+        })
+    };
+
+    if !options.host.contains_key(&options.default_host) {
+        problem(format!("Unknown default host {}", options.default_host));
+    }
+
+    for route in &options.routes {
+        if !options.host.contains_key(&route.host) {
+            problem(format!("Unknown host {} in {route}", route.host));
+        }
+    }
+
+    for (name, spec) in &options.alias {
+        if let Some(host) = spec.host_name() {
+            if !options.host.contains_key(host) {
+                problem(format!("Alias {name} uses unknown host {host}"));
+            }
+        }
+
+        // Aliases are not resolved recursively.
+        if options.alias.contains_key(spec.model_name()) && spec.model_name() != name {
+            problem(format!(
+                "Alias {name} refers to another alias {}, which will not be resolved",
+                spec.model_name()
+            ));
+        }
+    }
+
+    problems
+}
+
+fn check_template(config: &Config, template: &ParsedTemplate) -> Vec<Problem> {
+    let location = template.definition_path.display().to_string();
+    let mut problems = vec![];
+    let mut problem = |message: String| {
+        problems.push(Problem {
+            location: location.clone(),
+            message,
+        })
+    };
+
+    let mut sources = vec![(&template.path, &template.template)];
+    if let Some((system_path, system_template)) = template.system.as_ref() {
+        sources.push((system_path, system_template));
+    }
+
+    let mut referenced = BTreeSet::new();
+    let mut parsed_all = true;
+    for (path, source) in sources {
+        match tera::Template::new(&path.display().to_string(), None, source) {
+            Ok(parsed) => {
+                let mut visitor = VariableVisitor::default();
+                visitor.visit_nodes(&parsed.ast, &HashSet::new());
+                referenced.extend(visitor.referenced);
+            }
+            Err(e) => {
+                parsed_all = false;
+                problem(format!(
+                    "Template syntax error in {}: {}",
+                    path.display(),
+                    std::error::Error::source(&e)
+                        .map(|source| source.to_string())
+                        .unwrap_or_else(|| e.to_string())
+                ));
+            }
+        }
+    }
+
+    let options = &template.input.options;
+    let declared = options
+        .keys()
+        .map(String::as_str)
+        .chain(std::iter::once("extra"))
+        .collect::<Vec<_>>();
+
+    // Without the whole template, the variable references aren't known.
+    if parsed_all {
+        let mut unused = options
+            .iter()
+            // Images are sent to the model directly, so they don't need to be referenced.
+            .filter(|(name, option)| {
+                option.option_type != OptionType::Image && !referenced.contains(name.as_str())
+            })
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        unused.sort();
+        for name in unused {
+            problem(format!("Option {name} is never used in the template"));
+        }
+
+        for name in &referenced {
+            if declared.contains(&name.as_str()) || BUILTIN_VARIABLES.contains(&name.as_str()) {
+                continue;
+            }
+
+            let suggestion = declared
+                .iter()
+                .filter(|d| edit_distance(d, name) <= 2)
+                .min_by_key(|d| edit_distance(d, name))
+                .map(|d| format!(". Did you mean {d}?"))
+                .unwrap_or_default();
+            problem(format!(
+                "Variable {name} is used in the template but is not an option{suggestion}"
+            ));
+        }
+    }
+
+    for arg in &template.input.model.context.trim_args {
+        if !declared.contains(&arg.as_str()) {
+            problem(format!("trim_args contains {arg}, which is not an option"));
+        }
+    }
+
+    let mut model_options = config.model.clone();
+    model_options.update_from_model_input(&template.input.model);
+    for model in model_options.model_chain() {
+        if let Err(e) = model.api_host() {
+            problem(format!("Model {}: {}", model.model, error_message(&e)));
+        }
+    }
+
+    for (name, spec) in &template.input.model.alias {
+        if let Some(host) = spec.host_name() {
+            if !model_options.host.contains_key(host) {
+                problem(format!("Alias {name} uses unknown host {host}"));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Walks a Tera template to find the variables it references from the context.
+#[derive(Default)]
+struct VariableVisitor {
+    referenced: BTreeSet<String>,
+}
+
+impl VariableVisitor {
+    fn visit_nodes(&mut self, nodes: &[Node], locals: &HashSet<String>) {
+        // `set` adds variables for the rest of the current scope.
+        let mut locals = locals.clone();
+        for node in nodes {
+            match node {
+                Node::VariableBlock(_, expr) => self.visit_expr(expr, &locals),
+                Node::Set(_, set) => {
+                    self.visit_expr(&set.value, &locals);
+                    locals.insert(set.key.clone());
+                }
+                Node::MacroDefinition(_, definition, _) => {
+                    let mut macro_locals = HashSet::new();
+                    for (arg, default) in &definition.args {
+                        if let Some(default) = default {
+                            self.visit_expr(default, &macro_locals);
+                        }
+                        macro_locals.insert(arg.clone());
+                    }
+                    self.visit_nodes(&definition.body, &macro_locals);
+                }
+                Node::FilterSection(_, section, _) => {
+                    for arg in section.filter.args.values() {
+                        self.visit_expr(arg, &locals);
+                    }
+                    self.visit_nodes(&section.body, &locals);
+                }
+                Node::Block(_, block, _) => self.visit_nodes(&block.body, &locals),
+                Node::Forloop(_, forloop, _) => {
+                    self.visit_expr(&forloop.container, &locals);
+                    let mut loop_locals = locals.clone();
+                    loop_locals.insert(forloop.value.clone());
+                    if let Some(key) = forloop.key.as_ref() {
+                        loop_locals.insert(key.clone());
+                    }
+                    self.visit_nodes(&forloop.body, &loop_locals);
+                    if let Some(empty_body) = forloop.empty_body.as_ref() {
+                        self.visit_nodes(empty_body, &locals);
+                    }
+                }
+                Node::If(if_node, _) => {
+                    for (_, condition, body) in &if_node.conditions {
+                        self.visit_expr(condition, &locals);
+                        self.visit_nodes(body, &locals);
+                    }
+                    if let Some((_, body)) = if_node.otherwise.as_ref() {
+                        self.visit_nodes(body, &locals);
+                    }
+                }
+                Node::Super
+                | Node::Text(_)
+                | Node::Extends(..)
+                | Node::Include(..)
+                | Node::ImportMacro(..)
+                | Node::Raw(..)
+                | Node::Break(_)
+                | Node::Continue(_)
+                | Node::Comment(..) => {}
+            }
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr, locals: &HashSet<String>) {
+        for filter in &expr.filters {
+            for arg in filter.args.values() {
+                self.visit_expr(arg, locals);
+            }
+        }
+
+        self.visit_expr_val(&expr.val, locals);
+    }
+
+    fn visit_expr_val(&mut self, val: &ExprVal, locals: &HashSet<String>) {
+        match val {
+            ExprVal::Ident(ident) => self.visit_ident(ident, locals),
+            ExprVal::Math(math) => {
+                self.visit_expr(&math.lhs, locals);
+                self.visit_expr(&math.rhs, locals);
+            }
+            ExprVal::Logic(logic) => {
+                self.visit_expr(&logic.lhs, locals);
+                self.visit_expr(&logic.rhs, locals);
+            }
+            ExprVal::Test(test) => {
+                self.visit_ident(&test.ident, locals);
+                for arg in &test.args {
+                    self.visit_expr(arg, locals);
+                }
+            }
+            ExprVal::MacroCall(call) => {
+                for arg in call.args.values() {
+                    self.visit_expr(arg, locals);
+                }
+            }
+            ExprVal::FunctionCall(call) => {
+                for arg in call.args.values() {
+                    self.visit_expr(arg, locals);
+                }
+            }
+            ExprVal::Array(values) => {
+                for value in values {
+                    self.visit_expr(value, locals);
+                }
+            }
+            ExprVal::StringConcat(concat) => {
+                for value in &concat.values {
+                    self.visit_expr_val(value, locals);
+                }
+            }
+            ExprVal::In(in_expr) => {
+                self.visit_expr(&in_expr.lhs, locals);
+                self.visit_expr(&in_expr.rhs, locals);
+            }
+            ExprVal::String(_) | ExprVal::Int(_) | ExprVal::Float(_) | ExprVal::Bool(_) => {}
+        }
+    }
+
+    /// Record the variable at the root of an identifier such as `a.b[c].d`, along with any
+    /// variables used inside its brackets.
+    fn visit_ident(&mut self, ident: &str, locals: &HashSet<String>) {
+        let root = ident.split(['.', '[']).next().unwrap_or_default();
+        if !root.is_empty() && !locals.contains(root) {
+            self.referenced.insert(root.to_string());
+        }
+
+        for inner in ident.split('[').skip(1) {
+            let inner = inner.split(']').next().unwrap_or_default();
+            let is_literal =
+                inner.starts_with(['"', '\'']) || inner.starts_with(|c: char| c.is_ascii_digit());
+            if !inner.is_empty() && !is_literal {
+                self.visit_ident(inner, locals);
+            }
+        }
+    }
+}
+
+/// The Levenshtein distance between two strings, used to suggest likely misspellings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a_char != *b_char);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(previous + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Check all the templates, print any problems, and return an error if there were any.
+pub fn run_check(base_dir: PathBuf) -> Result<(), Report<Error>> {
+    let problems = check_templates(base_dir)?;
+    for problem in &problems {
+        println!("{}: {}", problem.location, problem.message);
+    }
+
+    if problems.is_empty() {
+        println!("No problems found");
+        Ok(())
+    } else {
+        Err(Report::new(Error::CheckFailed))
+            .attach_printable(format!("Found {} problems", problems.len()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    fn referenced(template: &str) -> Vec<String> {
+        let parsed = tera::Template::new("test", None, template).expect("parsing template");
+        let mut visitor = VariableVisitor::default();
+        visitor.visit_nodes(&parsed.ast, &HashSet::new());
+        visitor.referenced.into_iter().collect()
+    }
+
+    #[test]
+    fn finds_variables() {
+        assert_eq!(
+            referenced(
+                r##"
+                {{ a.b }} {{ c[d] | default(value=e) }}
+                {% for item in items %}{{ item.name }} {{ loop.index }}{% endfor %}
+                {% set x = y %}{{ x }}
+                {% if f is defined and g > 1 %}{% endif %}
+                "##
+            ),
+            vec!["a", "c", "d", "e", "f", "g", "items", "loop", "y"]
+        );
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(edit_distance("extra", "extra"), 0);
+        assert_eq!(edit_distance("extra", "exta"), 1);
+        assert_eq!(edit_distance("extra", "xetra"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn check_problems() {
+        let config = Config::from_directory(base_dir("check")).expect("loading config");
+        let template = config.find_template("problems").expect("loading template");
+        let messages = check_template(&config, &template)
+            .into_iter()
+            .map(|p| p.message)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            messages,
+            vec![
+                "Option unused is never used in the template",
+                "Variable exta is used in the template but is not an option. Did you mean extra?",
+                "Variable undeclared is used in the template but is not an option",
+                "trim_args contains missing, which is not an option",
+                "Model some-model on nowhere: Unknown model host nowhere",
+                "Model backup on elsewhere: Unknown model host elsewhere",
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        let config = Config::from_directory(base_dir("check")).expect("loading config");
+        let template = config
+            .find_template("syntax_error")
+            .expect("loading template");
+        let problems = check_template(&config, &template);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.starts_with("Template syntax error"));
+    }
+
+    #[test]
+    fn whole_directory() {
+        let problems = check_templates(base_dir("check")).expect("checking templates");
+        assert!(problems.contains(&Problem {
+            location: "configuration".to_string(),
+            message: "Unknown host anthropic in route `claude-*`".to_string(),
+        }));
+
+        let locations = problems
+            .iter()
+            .map(|p| {
+                PathBuf::from(&p.location)
+                    .file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| p.location.clone())
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            locations,
+            [
+                "bad_toml.pb.toml",
+                "configuration",
+                "missing_path.pb.toml",
+                "problems.pb.toml",
+                "syntax_error.pb.toml"
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect()
+        );
+    }
+}
@@ -4,25 +4,33 @@ use std::{
 };
 
 use error_stack::{Report, ResultExt};
+use itertools::Itertools;
 use serde::Deserialize;
 
 use crate::{
+    env::expand_env_vars,
     error::Error,
     global_config::global_config_dirs,
     hosts::{HostDefinition, HostDefinitionInput},
     model::{ModelOptions, ModelOptionsInput},
+    model_info::{ModelInfo, ModelRegistry},
     option::overwrite_option_from_option,
+    provenance::{join_key, MergedKeys, Origin, Provenance},
+    route::{builtin_routes, Route, RouteInput},
     template::ParsedTemplate,
 };
 
+/// The configuration files to look for in each directory. Only the first one found is used.
+const CONFIG_FILES: [&str; 2] = ["promptbox.toml", "promptbox/promptbox.toml"];
+
 fn default_template_dirs() -> Vec<PathBuf> {
     vec![PathBuf::from(".")]
 }
 
 #[derive(Deserialize, Debug, Default)]
 pub struct ConfigInput {
-    /// One or more globs that define where to look for templates.
-    /// Defaults to ./promptbox, or ./ if the config file is in ./promptbox
+    /// One or more globs that define where to look for templates. `~` and environment variables
+    /// are expanded. Defaults to ./promptbox, or ./ if the config file is in ./promptbox
     #[serde(default = "default_template_dirs")]
     pub templates: Vec<PathBuf>,
     /// Stop recursing through parent directories if a config file is found with `top_level = true`
@@ -36,14 +44,71 @@ pub struct ConfigInput {
     #[serde(default)]
     pub host: HashMap<String, HostDefinitionInput>,
     /// The default model host to use. If absent, ollama is the default.
-    /// GPT 3.5/4 models will always use OpenAI as the default if not explicitly set otherwise.
+    /// GPT 3.5/4 models use OpenAI through the built-in routes, unless another route matches.
+    pub default_host: Option<String>,
+    /// Rules that choose a host based on the model name. Routes from closer configuration files
+    /// are checked first.
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteInput>,
+    /// Metadata about models, which adds to or replaces the built-in values.
+    #[serde(default)]
+    pub models: HashMap<String, ModelInfo>,
+    /// Named sets of model options, selected with `--profile` or `PROMPTBOX_PROFILE`.
+    #[serde(default, rename = "profile")]
+    pub profiles: HashMap<String, ProfileInput>,
+    /// The configuration files that were merged into this one, in merge order.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+    /// Where each value was set
+    #[serde(skip)]
+    pub provenance: Provenance,
+    /// Problems found while loading the configuration that don't prevent it from being used
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+/// A `[profile.<name>]` section, which overrides the configuration's model options when selected.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ProfileInput {
+    #[serde(flatten)]
+    pub model: ModelOptionsInput,
     pub default_host: Option<String>,
 }
 
+impl ProfileInput {
+    /// For any members that are `None` in this profile, use the value from `other`. Returns the
+    /// keys of the values that were taken from `other`.
+    fn merge_defaults(&mut self, other: &ProfileInput) -> MergedKeys {
+        // The model options are flattened into the profile's table.
+        let mut merged = self.model.merge_defaults(&other.model);
+        merged.update_if_none("default_host", &mut self.default_host, &other.default_host);
+        merged
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Config {
     pub template_dirs: Vec<PathBuf>,
     pub model: ModelOptions,
+    /// The configuration files that were read, closest first
+    pub config_files: Vec<PathBuf>,
+    /// Where each configuration value was set
+    pub provenance: Provenance,
+    /// Problems found while loading the configuration, such as template directories that don't
+    /// exist
+    pub warnings: Vec<String>,
+    pub profiles: HashMap<String, ProfileInput>,
+    /// The name of the profile applied to `model`, if any
+    pub profile: Option<String>,
+    /// The configuration before any profile is applied
+    unprofiled: Unprofiled,
+}
+
+#[derive(Debug, Default)]
+struct Unprofiled {
+    model: ModelOptionsInput,
+    default_host: String,
+    provenance: Provenance,
 }
 
 impl Config {
@@ -77,6 +142,126 @@ impl Config {
         Self::create_config(config)
     }
 
+    /// Create a [Config] with [Config::from_directory], and then apply the profile, if one is
+    /// given.
+    pub fn from_directory_with_profile(
+        start_dir: PathBuf,
+        profile: Option<&str>,
+    ) -> Result<Self, Report<Error>> {
+        let mut config = Self::from_directory(start_dir)?;
+        if let Some(profile) = profile {
+            config.apply_profile(profile)?;
+        }
+        Ok(config)
+    }
+
+    /// The model options with a profile applied. The profile's options take precedence over the
+    /// configuration's.
+    pub fn profile_model_options(&self, name: &str) -> Result<ModelOptions, Report<Error>> {
+        self.merge_profile(name).map(|(options, _)| options)
+    }
+
+    /// Create the model options for a profile, and return the keys of the model options that
+    /// were taken from the configuration because the profile doesn't set them.
+    fn merge_profile(&self, name: &str) -> Result<(ModelOptions, MergedKeys), Report<Error>> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| Report::new(Error::UnknownProfile(name.to_string())))?;
+
+        let mut input = profile.model.clone();
+        let mut from_config = MergedKeys::default();
+        from_config.extend_under("model", input.merge_defaults(&self.unprofiled.model));
+        let default_host = match profile.default_host.as_ref() {
+            Some(default_host) => default_host.clone(),
+            None => {
+                from_config.add("default_host");
+                self.unprofiled.default_host.clone()
+            }
+        };
+
+        let options = ModelOptions::new(
+            input,
+            self.model.host.clone(),
+            default_host,
+            self.model.routes.clone(),
+            self.model.models.clone(),
+        );
+        Ok((options, from_config))
+    }
+
+    /// Apply a profile to the model options, replacing any profile that was already applied.
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), Report<Error>> {
+        let (options, from_config) = self.merge_profile(name)?;
+        self.model = options;
+
+        // Start with the profile's values, moved to the keys they override, and then add the
+        // values that came from the configuration instead.
+        let unprofiled = &self.unprofiled.provenance;
+        let mut provenance = unprofiled.clone();
+        provenance.remove("model");
+        provenance.remove("default_host");
+
+        let prefix = format!("{}.", join_key("profile", name));
+        for (key, value, origin) in unprofiled.iter() {
+            let Some(rest) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let key = if rest == "default_host" {
+                rest.to_string()
+            } else {
+                format!("model.{rest}")
+            };
+            provenance.set(&key, value.clone(), origin.clone());
+        }
+
+        provenance.take_merged(unprofiled, &from_config);
+        self.provenance = provenance;
+        self.profile = Some(name.to_string());
+        Ok(())
+    }
+
+    /// List each file that [Config::from_directory] looks for, in order, and whether it was used.
+    pub fn search_paths(start_dir: PathBuf) -> Result<Vec<(PathBuf, &'static str)>, Report<Error>> {
+        let mut paths = Vec::new();
+        let mut use_global_config = None;
+
+        let mut current_dir = start_dir;
+        loop {
+            let config = ConfigInput::from_dir(&current_dir)?;
+            let top_level = config.as_ref().is_some_and(|c| c.top_level);
+            if let Some(config) = config.as_ref() {
+                overwrite_option_from_option(&mut use_global_config, &config.use_global_config);
+            }
+            let loaded_status = if top_level {
+                "loaded, top level"
+            } else {
+                "loaded"
+            };
+            add_search_paths(&mut paths, &current_dir, config.as_ref(), loaded_status);
+
+            if top_level || !current_dir.pop() {
+                break;
+            }
+        }
+
+        for global_config_dir in global_config_dirs() {
+            if use_global_config.unwrap_or(true) {
+                let config = ConfigInput::from_dir(&global_config_dir)?;
+                add_search_paths(&mut paths, &global_config_dir, config.as_ref(), "loaded");
+            } else {
+                for file in CONFIG_FILES {
+                    paths.push((
+                        global_config_dir.join(file),
+                        "skipped, use_global_config is false",
+                    ));
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
     fn create_config(input: ConfigInput) -> Result<Self, Report<Error>> {
         let mut hosts = HostDefinition::builtin();
 
@@ -91,15 +276,45 @@ impl Config {
             }
         }
 
+        let mut provenance = input.provenance;
+        for (name, host) in hosts.iter_mut() {
+            for (field, var, value) in host.update_from_env(name) {
+                let key = join_key(&join_key("host", name), field);
+                provenance.set(&key, toml::Value::String(value), Origin::Env(var));
+            }
+        }
+
+        let mut routes = input
+            .routes
+            .iter()
+            .map(Route::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        routes.extend(builtin_routes());
+
+        let model_input = input.model.unwrap_or_default();
+        let default_host = input
+            .default_host
+            .unwrap_or_else(|| HostDefinition::default_host().to_string());
+
         Ok(Self {
             template_dirs: input.templates,
+            config_files: input.files,
+            provenance: provenance.clone(),
+            warnings: input.warnings,
             model: ModelOptions::new(
-                input.model.unwrap_or_default(),
+                model_input.clone(),
                 hosts,
-                input
-                    .default_host
-                    .unwrap_or_else(|| HostDefinition::default_host().to_string()),
+                default_host.clone(),
+                routes,
+                ModelRegistry::new(input.models),
             ),
+            profiles: input.profiles,
+            profile: None,
+            unprofiled: Unprofiled {
+                model: model_input,
+                default_host,
+                provenance,
+            },
         })
     }
 
@@ -116,25 +331,81 @@ impl Config {
 
         Err(Report::from(Error::TemplateNotFound))
     }
+
+    /// List the names of all the templates in the template directories. When a name exists in
+    /// multiple directories, it is only returned once, as with [Config::find_template].
+    pub fn list_templates(&self) -> Vec<String> {
+        let mut names = self
+            .template_files()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Find every template file in the template directories, along with its template name.
+    pub fn template_files(&self) -> Vec<(String, PathBuf)> {
+        let mut files = vec![];
+        for dir in &self.template_dirs {
+            let mut dir_files = vec![];
+            find_template_files(dir, dir, &mut dir_files);
+            dir_files.sort();
+            files.extend(dir_files);
+        }
+        files
+    }
+}
+
+/// Recursively find template files in `dir`, adding their names relative to `root`.
+/// Hidden directories are skipped.
+fn find_template_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if path.is_dir() {
+            if !file_name.starts_with('.') {
+                find_template_files(root, &path, files);
+            }
+        } else if let Some(name) = file_name.strip_suffix(".pb.toml") {
+            let relative_dir = path
+                .parent()
+                .and_then(|parent| parent.strip_prefix(root).ok())
+                .unwrap_or_else(|| Path::new(""));
+            let name = relative_dir.join(name).to_string_lossy().into_owned();
+            files.push((name, path));
+        }
+    }
 }
 
 impl ConfigInput {
     /// Try to load a ConfigInput from a directory or the `promptbox` sudirectory.
     fn from_dir(dir: &Path) -> Result<Option<Self>, Report<Error>> {
-        let mut config_iter = ["promptbox.toml", "promptbox/promptbox.toml"]
-            .into_iter()
-            .filter_map(|p| {
-                let config_path = dir.join(p);
-                let contents = std::fs::read_to_string(&config_path).ok()?;
-                Some((config_path, contents))
-            });
+        let mut config_iter = CONFIG_FILES.into_iter().filter_map(|p| {
+            let config_path = dir.join(p);
+            let contents = std::fs::read_to_string(&config_path).ok()?;
+            Some((config_path, contents))
+        });
 
         let Some((config_path, contents)) = config_iter.next() else {
             // If there is a directory named promptbox, but without a config file, use that.
             let promptbox_dir = dir.join("promptbox");
             if promptbox_dir.is_dir() {
+                let mut provenance = Provenance::default();
+                provenance.set(
+                    "templates",
+                    path_value(&promptbox_dir),
+                    Origin::Directory(promptbox_dir.clone()),
+                );
                 return Ok(Some(ConfigInput {
                     templates: vec![promptbox_dir],
+                    provenance,
                     ..Default::default()
                 }));
             }
@@ -142,46 +413,222 @@ impl ConfigInput {
             return Ok(None);
         };
 
+        let table: toml::Table = toml::from_str(&contents)
+            .change_context(Error::ParseConfig)
+            .attach_printable_lazy(|| config_path.display().to_string())?;
         let mut new_config: ConfigInput = toml::from_str(&contents)
             .change_context(Error::ParseConfig)
             .attach_printable_lazy(|| config_path.display().to_string())?;
 
+        // Every value in the file starts out here, but only the values that merging actually uses
+        // are carried into the final configuration.
+        new_config.provenance =
+            Provenance::from_table("", &table, &Origin::File(config_path.clone()));
+
         let base_dir = config_path.parent().expect("path had no directory");
-        new_config.resolve_template_dirs(base_dir);
+        new_config.resolve_template_dirs(base_dir, &config_path);
+        new_config.resolve_host_paths(base_dir, &config_path);
+
+        new_config.files = vec![config_path];
         Ok(Some(new_config))
     }
 
-    /// Convert the template directory references to absolute paths
-    fn resolve_template_dirs(&mut self, base_dir: &Path) {
-        for template in self.templates.iter_mut() {
-            if template.is_relative() {
-                if let Ok(full_path) = std::fs::canonicalize(base_dir.join(&template)) {
-                    *template = full_path;
+    /// Expand the template directory globs and convert them to absolute paths. Entries that
+    /// don't match any directory are kept so that they can be reported, and add a warning.
+    fn resolve_template_dirs(&mut self, base_dir: &Path, config_path: &Path) {
+        let patterns = std::mem::take(&mut self.templates);
+        for pattern in patterns {
+            let pattern = pattern.to_string_lossy();
+            let expanded = match expand_env_vars(&pattern) {
+                Ok(expanded) => expand_home(&expanded),
+                Err(var) => {
+                    self.warnings.push(format!(
+                        "{}: templates entry {pattern} uses unset environment variable {var}",
+                        config_path.display()
+                    ));
+                    continue;
+                }
+            };
+            let path = base_dir.join(expanded);
+
+            let is_glob = path.to_string_lossy().contains(['*', '?', '[']);
+            let dirs = if is_glob {
+                glob::glob(&path.to_string_lossy())
+                    .map(|paths| {
+                        paths
+                            .flatten()
+                            .filter(|path| path.is_dir())
+                            .map(|path| std::fs::canonicalize(&path).unwrap_or(path))
+                            .sorted()
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default()
+            } else {
+                std::fs::canonicalize(&path)
+                    .ok()
+                    .filter(|path| path.is_dir())
+                    .into_iter()
+                    .collect()
+            };
+
+            if dirs.is_empty() {
+                self.warnings.push(format!(
+                    "{}: templates entry {pattern} does not match any directories",
+                    config_path.display()
+                ));
+                if !is_glob {
+                    self.templates.push(path);
                 }
             }
+
+            self.templates.extend(dirs);
+        }
+
+        // This includes the default when the file doesn't set any.
+        let templates = self.templates.iter().map(|dir| path_value(dir)).collect();
+        self.provenance.set(
+            "templates",
+            toml::Value::Array(templates),
+            Origin::File(config_path.to_path_buf()),
+        );
+    }
+
+    /// Resolve paths in host definitions relative to the configuration file.
+    fn resolve_host_paths(&mut self, base_dir: &Path, config_path: &Path) {
+        let origin = Origin::File(config_path.to_path_buf());
+        for (name, host) in self.host.iter_mut() {
+            let prefix = join_key("host", name);
+            let mut resolved = |key: &str, value: toml::Value| {
+                self.provenance
+                    .set(&format!("{prefix}.{key}"), value, origin.clone());
+            };
+
+            if let Some(command) = host.command.as_mut() {
+                resolve_command_path(&mut command.program, base_dir);
+                resolved("command.program", command_value(&command.program));
+            }
+            if let Some(command) = host.api_key_command.as_mut() {
+                resolve_command_path(command, base_dir);
+                resolved("api_key_command", command_value(command));
+            }
+
+            if let Some(file) = host.api_key_file.as_mut() {
+                if file.is_relative() {
+                    *file = base_dir.join(&file);
+                }
+                resolved("api_key_file", path_value(file));
+            }
+
+            if let Some(fixtures) = host.mock.as_mut().and_then(|mock| mock.fixtures.as_mut()) {
+                if fixtures.is_relative() {
+                    *fixtures = base_dir.join(&fixtures);
+                }
+                resolved("mock.fixtures", path_value(fixtures));
+            }
+
+            if let Some(ca_cert) = host.http.ca_cert.as_mut() {
+                if ca_cert.is_relative() {
+                    *ca_cert = base_dir.join(&ca_cert);
+                }
+                resolved("ca_cert", path_value(ca_cert));
+            }
         }
     }
 
     /// Merge in another ConfigInput, using only values which are not yet configured in `self`.
+    /// The origins of the values that are used are taken from `other` as well.
     fn merge(&mut self, other: ConfigInput) {
         self.templates.extend(other.templates);
+        self.routes.extend(other.routes);
+        self.files.extend(other.files);
+        self.warnings.extend(other.warnings);
+        self.provenance.append(&other.provenance, "templates");
+        self.provenance.append(&other.provenance, "route");
+
+        let mut merged = MergedKeys::default();
+        merged.overwrite_option_from_option(
+            "use_global_config",
+            &mut self.use_global_config,
+            &other.use_global_config,
+        );
+        merged.update_if_none("default_host", &mut self.default_host, &other.default_host);
 
-        overwrite_option_from_option(&mut self.use_global_config, &other.use_global_config);
+        if let Some(other_model) = other.model.as_ref() {
+            let model = self.model.get_or_insert_with(ModelOptionsInput::default);
+            merged.extend_under("model", model.merge_defaults(other_model));
+        }
 
-        if let Some(other_model) = other.model {
-            if let Some(model) = self.model.as_mut() {
-                model.merge_defaults(&other_model);
-            } else {
-                self.model = Some(other_model);
-            }
+        for (name, other_info) in &other.models {
+            let info = self.models.entry(name.clone()).or_default();
+            merged.extend_under(&join_key("models", name), info.merge_defaults(other_info));
         }
 
-        for (key, other_host) in other.host {
-            if let Some(host) = self.host.get_mut(&key) {
-                host.merge_from_input(&other_host);
-            } else {
-                self.host.insert(key, other_host);
-            }
+        for (name, other_profile) in &other.profiles {
+            let profile = self.profiles.entry(name.clone()).or_default();
+            merged.extend_under(
+                &join_key("profile", name),
+                profile.merge_defaults(other_profile),
+            );
+        }
+
+        for (key, other_host) in &other.host {
+            let host = self.host.entry(key.clone()).or_default();
+            merged.extend_under(&join_key("host", key), host.merge_from_input(other_host));
+        }
+
+        self.provenance.take_merged(&other.provenance, &merged);
+    }
+}
+
+fn add_search_paths(
+    paths: &mut Vec<(PathBuf, &'static str)>,
+    dir: &Path,
+    config: Option<&ConfigInput>,
+    loaded_status: &'static str,
+) {
+    let loaded = config.and_then(|config| config.files.first());
+    for file in CONFIG_FILES {
+        let path = dir.join(file);
+        let status = if Some(&path) == loaded {
+            loaded_status
+        } else if path.exists() {
+            "ignored, another file in this directory was loaded"
+        } else {
+            "not found"
+        };
+        paths.push((path, status));
+    }
+}
+
+/// Replace a leading `~` with the home directory.
+fn expand_home(path: &str) -> PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some("") => "",
+        Some(rest) if rest.starts_with(std::path::is_separator) => &rest[1..],
+        _ => return PathBuf::from(path),
+    };
+
+    match etcetera::home_dir() {
+        Ok(home) => home.join(rest),
+        Err(_) => PathBuf::from(path),
+    }
+}
+
+fn path_value(path: &Path) -> toml::Value {
+    toml::Value::String(path.display().to_string())
+}
+
+fn command_value(command: &[String]) -> toml::Value {
+    toml::Value::Array(command.iter().cloned().map(toml::Value::String).collect())
+}
+
+/// Commands given as a relative path, such as `./run-model.sh`, are relative to the configuration
+/// file. Bare command names are looked up in the PATH as usual.
+fn resolve_command_path(command: &mut [String], base_dir: &Path) {
+    if let Some(program) = command.first_mut() {
+        let path = Path::new(program.as_str());
+        if path.is_relative() && path.components().count() > 1 {
+            *program = base_dir.join(path).to_string_lossy().into_owned();
         }
     }
 }
@@ -199,6 +646,13 @@ mod tests {
             PathBuf::from(BASE_DIR),
         ];
         assert_eq!(config.template_dirs, expected_dirs);
+        assert_eq!(
+            config.config_files,
+            vec![
+                base_dir("config_in_subdir/promptbox/promptbox.toml"),
+                base_dir("promptbox.toml"),
+            ]
+        );
     }
 
     #[test]
@@ -213,6 +667,98 @@ mod tests {
         assert_eq!(config.template_dirs, expected_dirs);
     }
 
+    #[test]
+    fn template_globs() {
+        std::env::set_var("PROMPTBOX_TEST_GLOB_SHARED", "shared");
+        let config = Config::from_directory(base_dir("template_globs")).expect("loading config");
+        assert_eq!(
+            config.template_dirs,
+            vec![
+                base_dir("template_globs/teams/alpha/prompts"),
+                base_dir("template_globs/teams/beta/prompts"),
+                base_dir("template_globs/shared"),
+                base_dir("template_globs/missing"),
+            ]
+        );
+
+        let config_path = base_dir("template_globs/promptbox.toml");
+        let config_path = config_path.display();
+        assert_eq!(
+            config.warnings,
+            vec![
+                format!("{config_path}: templates entry missing does not match any directories"),
+                format!("{config_path}: templates entry nothing/* does not match any directories"),
+                format!(
+                    "{config_path}: templates entry ${{PROMPTBOX_TEST_GLOB_UNSET}}/prompts uses unset environment variable PROMPTBOX_TEST_GLOB_UNSET"
+                ),
+            ]
+        );
+
+        assert!(config.find_template("beta").is_ok());
+        assert!(config.find_template("gamma").is_err());
+    }
+
+    #[test]
+    fn home_dir_expansion() {
+        let home = etcetera::home_dir().unwrap();
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~/prompts"), home.join("prompts"));
+        assert_eq!(expand_home("~user/prompts"), PathBuf::from("~user/prompts"));
+        assert_eq!(expand_home("prompts/~"), PathBuf::from("prompts/~"));
+    }
+
+    #[test]
+    fn profiles() {
+        let mut config =
+            Config::from_directory(base_dir("profiles/child")).expect("loading config");
+        assert_eq!(config.model.model.model_name(), "gpt-4o");
+        assert_eq!(config.model.temperature, 0.2);
+
+        config.apply_profile("local").unwrap();
+        assert_eq!(config.profile.as_deref(), Some("local"));
+        assert_eq!(config.model.model.model_name(), "llama3");
+        assert_eq!(config.model.default_host, "ollama");
+        // The closer configuration file takes precedence within a profile.
+        assert_eq!(config.model.temperature, 0.1);
+        assert!(config.model.alias.contains_key("fast"));
+        let (_, _, origin) = config
+            .provenance
+            .iter()
+            .find(|(key, _, _)| *key == "model.temperature")
+            .unwrap();
+        assert_eq!(
+            origin,
+            &Origin::File(base_dir("profiles/child/promptbox.toml"))
+        );
+
+        // Applying another profile replaces the first one.
+        config.apply_profile("cloud").unwrap();
+        assert_eq!(config.model.model.model_name(), "gpt-4o");
+        assert_eq!(config.model.fallbacks.len(), 1);
+        assert_eq!(config.model.temperature, 0.2);
+        assert_eq!(config.model.default_host, HostDefinition::default_host());
+        assert!(config.model.alias.is_empty());
+
+        let err = config.apply_profile("nonexistent").unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::UnknownProfile(name) if name == "nonexistent"
+        ));
+    }
+
+    #[test]
+    fn default_host_from_config() {
+        let config =
+            Config::from_directory(base_dir("override_template/override")).expect("loading config");
+        assert_eq!(config.model.default_host, "my_ollama_host");
+        let (_, _, origin) = config
+            .provenance
+            .iter()
+            .find(|(key, _, _)| *key == "default_host")
+            .unwrap();
+        assert_eq!(origin, &Origin::File(base_dir("promptbox.toml")));
+    }
+
     #[test]
     fn malformed() {
         let err = Config::from_directory(base_dir("malformed_config"))
@@ -242,6 +788,7 @@ mod tests {
                     api_key: Some("foo_key".to_string()),
                     protocol: Some(crate::hosts::HostProtocol::OpenAi),
                     limit_context_length: Some(true),
+                    ..Default::default()
                 },
             )]),
             ..Default::default()
@@ -281,6 +828,7 @@ mod tests {
                         api_key: Some("foo_key".to_string()),
                         protocol: Some(crate::hosts::HostProtocol::OpenAi),
                         limit_context_length: Some(true),
+                        ..Default::default()
                     },
                 ),
                 (
@@ -319,6 +867,7 @@ mod tests {
                     api_key: Some("foo_key".to_string()),
                     protocol: Some(crate::hosts::HostProtocol::OpenAi),
                     limit_context_length: Some(true),
+                    ..Default::default()
                 },
             )]),
             ..Default::default()
@@ -0,0 +1,184 @@
+use std::{io::Write, path::PathBuf};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{config::Config, error::Error, provenance::MergedKeys};
+
+/// Print each configuration value with the file or environment variable that set it. If
+/// `profile` or `template` is given, their model options are applied as well.
+pub fn show_config(
+    base_dir: PathBuf,
+    template: Option<&str>,
+    profile: Option<&str>,
+    output: &mut impl Write,
+) -> Result<(), Report<Error>> {
+    let mut config = Config::from_directory_with_profile(base_dir, profile)?;
+    let mut provenance = config.provenance.clone();
+
+    if let Some(name) = template {
+        let template = config.find_template(name)?;
+        let mut merged = MergedKeys::default();
+        merged.extend_under(
+            "model",
+            config.model.update_from_model_input(&template.input.model),
+        );
+        provenance.take_merged(&template.provenance, &merged);
+    }
+
+    let settings = provenance
+        .iter()
+        .map(|(key, value, origin)| (format!("{key} = {value}"), origin))
+        .collect::<Vec<_>>();
+    let width = settings
+        .iter()
+        .map(|(setting, _)| setting.len())
+        .max()
+        .unwrap_or(0);
+
+    for (setting, origin) in settings {
+        writeln!(output, "{setting:width$}  # {origin}").change_context(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+/// Print each configuration file that PromptBox looks for, and whether it was used.
+pub fn show_config_paths(base_dir: PathBuf, output: &mut impl Write) -> Result<(), Report<Error>> {
+    for (path, status) in Config::search_paths(base_dir)? {
+        writeln!(output, "{} ({status})", path.display()).change_context(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{show_config, show_config_paths};
+    use crate::tests::base_dir;
+
+    fn output(f: impl FnOnce(&mut Vec<u8>)) -> Vec<String> {
+        let mut output = Vec::new();
+        f(&mut output);
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn show_with_template() {
+        let lines = output(|out| {
+            show_config(
+                base_dir("override_template/override"),
+                Some("routed"),
+                None,
+                out,
+            )
+            .unwrap()
+        });
+        let config = base_dir("promptbox.toml");
+        let template = base_dir("routed.pb.toml");
+
+        assert!(lines.contains(&format!(
+            "templates = \"{}\" # {}",
+            base_dir("override_template/override").display(),
+            base_dir("override_template/override/promptbox.toml").display()
+        )));
+        assert!(lines.contains(&format!(
+            "default_host = \"my_ollama_host\" # {}",
+            config.display()
+        )));
+        assert!(lines.contains(&format!("model.temperature = 0.4 # {}", config.display())));
+        assert!(lines.contains(&format!(
+            "host.mock_fixtures.mock.fixtures = \"{}\" # {}",
+            base_dir("mock_fixtures.toml").display(),
+            config.display()
+        )));
+        let model = lines
+            .iter()
+            .find(|line| line.starts_with("model.model = "))
+            .unwrap();
+        assert!(model.starts_with(r#"model.model = ["routed/small", "gpt-4-mock""#));
+        assert!(model.ends_with(&format!("# {}", template.display())));
+    }
+
+    #[test]
+    fn show_merged_values() {
+        let lines =
+            output(|out| show_config(base_dir("config_show/child"), None, None, out).unwrap());
+        let parent = base_dir("config_show/promptbox.toml");
+        let child = base_dir("config_show/child/promptbox.toml");
+
+        assert!(lines.contains(&format!("model.temperature = 0.2 # {}", child.display())));
+        assert!(lines.contains(&format!("model.top_p = 0.9 # {}", parent.display())));
+        assert!(lines.contains(&format!(
+            "host.local.api_key_file = \"{}\" # {}",
+            base_dir("config_show/keys/local.txt").display(),
+            parent.display()
+        )));
+        assert!(
+            !lines
+                .iter()
+                .any(|line| line.contains("temprature") || line.contains("templaets")),
+            "unknown keys should not be shown: {lines:?}"
+        );
+    }
+
+    #[test]
+    fn show_with_profile() {
+        let lines = output(|out| {
+            show_config(base_dir("profiles/child"), None, Some("local"), out).unwrap()
+        });
+        let parent = base_dir("profiles/promptbox.toml");
+        let child = base_dir("profiles/child/promptbox.toml");
+
+        assert!(lines.contains(&format!("model.model = \"llama3\" # {}", parent.display())));
+        assert!(lines.contains(&format!("model.temperature = 0.1 # {}", child.display())));
+        assert!(lines.contains(&format!(
+            "model.alias.fast = \"llama3:8b\" # {}",
+            child.display()
+        )));
+        assert!(lines.contains(&format!("default_host = \"ollama\" # {}", parent.display())));
+        assert!(!lines.contains(&format!("model.model = \"gpt-4o\" # {}", parent.display())));
+    }
+
+    #[test]
+    fn paths() {
+        let lines =
+            output(|out| show_config_paths(base_dir("override_template/override"), out).unwrap());
+        assert_eq!(
+            &lines[..6],
+            &[
+                format!(
+                    "{} (loaded)",
+                    base_dir("override_template/override/promptbox.toml").display()
+                ),
+                format!(
+                    "{} (not found)",
+                    base_dir("override_template/override/promptbox/promptbox.toml").display()
+                ),
+                format!(
+                    "{} (loaded)",
+                    base_dir("override_template/promptbox.toml").display()
+                ),
+                format!(
+                    "{} (not found)",
+                    base_dir("override_template/promptbox/promptbox.toml").display()
+                ),
+                format!(
+                    "{} (loaded, top level)",
+                    base_dir("promptbox.toml").display()
+                ),
+                format!(
+                    "{} (not found)",
+                    base_dir("promptbox/promptbox.toml").display()
+                ),
+            ]
+        );
+        // The test configuration disables the global configuration.
+        assert!(lines[6..]
+            .iter()
+            .all(|line| line.ends_with("(skipped, use_global_config is false)")));
+    }
+}
@@ -5,15 +5,22 @@ use error_stack::{Report, ResultExt};
 use serde::{Deserialize, Serialize};
 use tokenizers::Encoding;
 
-use crate::{model::ModelOptions, option::update_if_none, Error};
+use crate::{model::ModelOptions, provenance::MergedKeys, Error};
 
-struct Tokenizer(tokenizers::Tokenizer);
+pub struct Tokenizer(tokenizers::Tokenizer);
+
+/// The tokenizer for models that don't configure one in the model registry. This isn't accurate
+/// for everything but most models are using a similar config.
+pub const DEFAULT_TOKENIZER: &str = "TheBloke/Llama-2-70B-fp16";
 
 impl Tokenizer {
+    #[cfg(test)]
     fn new() -> Result<Self, Error> {
-        // This isn't accurate for everything but most models are using a similar config.
-        // Eventually it would be better to get the proper tokenizer for each model.
-        let tokenizer = tokenizers::Tokenizer::from_pretrained("TheBloke/Llama-2-70B-fp16", None)
+        Self::from_pretrained(DEFAULT_TOKENIZER)
+    }
+
+    pub fn from_pretrained(name: &str) -> Result<Self, Error> {
+        let tokenizer = tokenizers::Tokenizer::from_pretrained(name, None)
             .map_err(|e| Error::Tokenizer(e.to_string()))?;
         Ok(Self(tokenizer))
     }
@@ -120,15 +127,27 @@ pub struct ContextOptionsInput {
 }
 
 impl ContextOptionsInput {
-    pub fn merge_defaults(&mut self, other: &ContextOptionsInput) {
-        update_if_none(&mut self.limit, &other.limit);
-        update_if_none(&mut self.keep, &other.keep);
-        update_if_none(&mut self.array_priority, &other.array_priority);
-        update_if_none(&mut self.reserve_output, &other.reserve_output);
+    pub fn merge_defaults(&mut self, other: &ContextOptionsInput) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        merged.update_if_none("limit", &mut self.limit, &other.limit);
+        merged.update_if_none("keep", &mut self.keep, &other.keep);
+        merged.update_if_none(
+            "array_priority",
+            &mut self.array_priority,
+            &other.array_priority,
+        );
+        merged.update_if_none(
+            "reserve_output",
+            &mut self.reserve_output,
+            &other.reserve_output,
+        );
 
         if !other.trim_args.is_empty() {
             self.trim_args = other.trim_args.clone();
+            merged.add("trim_args");
         }
+
+        merged
     }
 }
 
@@ -155,6 +174,14 @@ fn truncate_at<'a>(
     }
 }
 
+/// Count the number of tokens in some text, using the tokenizer with this name.
+pub fn count_tokens(text: &str, tokenizer_name: &str) -> Result<usize, Report<Error>> {
+    let tokenizer = Tokenizer::from_pretrained(tokenizer_name)
+        .attach_printable_lazy(|| format!("Loading tokenizer {tokenizer_name}"))?;
+    let encoded = tokenizer.encode(text)?;
+    Ok(encoded.len())
+}
+
 pub fn enforce_context_limit(
     model_options: &ModelOptions,
     template_path: &Path,
@@ -170,7 +197,10 @@ pub fn enforce_context_limit(
         return Ok(rendered);
     };
 
-    let tokenizer = Tokenizer::new().change_context(Error::PreparePrompt)?;
+    let tokenizer_name = model_options.tokenizer_name();
+    let tokenizer = Tokenizer::from_pretrained(tokenizer_name)
+        .change_context(Error::PreparePrompt)
+        .attach_printable_lazy(|| format!("Loading tokenizer {tokenizer_name}"))?;
     let encoded = tokenizer
         .encode(&rendered)
         .change_context(Error::PreparePrompt)?;
@@ -0,0 +1,274 @@
+use std::{io::Write, path::PathBuf, time::Duration};
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+
+use crate::{
+    cache::Cache,
+    config::Config,
+    context::Tokenizer,
+    error::error_message,
+    error::Error,
+    global_config::dotenv_files,
+    hosts::{HostDefinition, HostProtocol},
+    model::{ModelError, ModelOptions},
+};
+
+/// How long to wait for a host to respond when checking that it's reachable.
+const REACHABLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Print the configuration files and environment that PromptBox uses, and check that each host
+/// can be used.
+pub fn run_doctor(
+    base_dir: PathBuf,
+    profile: Option<&str>,
+    output: &mut impl Write,
+) -> Result<(), Report<Error>> {
+    let config = Config::from_directory_with_profile(base_dir.clone(), profile)?;
+
+    let mut lines = vec!["Configuration files:".to_string()];
+    lines.extend(path_lines(&config.config_files, false));
+    lines.push("Template directories:".to_string());
+    lines.extend(path_lines(&config.template_dirs, true));
+    lines.push("Environment files:".to_string());
+    lines.extend(path_lines(&dotenv_files(&base_dir), false));
+
+    lines.push(String::new());
+    lines.push("Hosts:".to_string());
+    // Check the hosts in parallel so that unreachable hosts don't add up their timeouts.
+    let host_lines = std::thread::scope(|scope| {
+        config
+            .model
+            .host
+            .iter()
+            .sorted_by_key(|(name, _)| *name)
+            .map(|(name, host)| scope.spawn(move || host_lines(name, host)))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_default())
+            .collect::<Vec<_>>()
+    });
+    lines.extend(host_lines);
+
+    lines.push(String::new());
+    if let Some(profile) = config.profile.as_deref() {
+        lines.push(format!("Profile: {profile}"));
+    }
+    lines.extend(default_model_lines(&config.model));
+    lines.push(String::new());
+    lines.push(cache_line());
+    lines.push(tokenizer_line(&config.model));
+
+    for line in lines {
+        writeln!(output, "{line}").change_context(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+fn path_lines(paths: &[PathBuf], directories: bool) -> Vec<String> {
+    if paths.is_empty() {
+        return vec!["  (none)".to_string()];
+    }
+
+    paths
+        .iter()
+        .map(|path| {
+            if directories && !path.is_dir() {
+                format!("  {} (missing)", path.display())
+            } else {
+                format!("  {}", path.display())
+            }
+        })
+        .collect()
+}
+
+fn host_lines(name: &str, host: &HostDefinition) -> Vec<String> {
+    let mut lines = match host.protocol {
+        HostProtocol::Mock | HostProtocol::Command => vec![format!("  {name}: local")],
+        _ => vec![
+            format!("  {name}: {}", host.endpoint),
+            format!("    Endpoint: {}", reachability(host)),
+        ],
+    };
+
+    lines.push(format!("    API key: {}", api_key_status(host)));
+    lines
+}
+
+/// Describe where the host's API key comes from, following the same order as
+/// [ApiKey::get](crate::hosts::api_key::ApiKey::get). Commands are not run.
+fn api_key_status(host: &HostDefinition) -> String {
+    if let Some(var) = host.api_key.as_ref() {
+        match std::env::var(var).ok().filter(|value| !value.is_empty()) {
+            Some(value) => return format!("{var} is set ({})", mask_secret(&value)),
+            None if host.api_key_file.is_none() && host.api_key_command.is_empty() => {
+                return format!("{var} is not set");
+            }
+            None => {}
+        }
+    }
+
+    if let Some(file) = host.api_key_file.as_ref() {
+        if file.is_file() {
+            format!("read from {}", file.display())
+        } else {
+            format!("file {} does not exist", file.display())
+        }
+    } else if !host.api_key_command.is_empty() {
+        format!("printed by `{}`", host.api_key_command.join(" "))
+    } else {
+        "not required".to_string()
+    }
+}
+
+/// Show only the start and end of a secret, or nothing for short secrets.
+fn mask_secret(secret: &str) -> String {
+    let chars = secret.chars().collect::<Vec<_>>();
+    if chars.len() < 12 {
+        return "****".to_string();
+    }
+
+    let start = chars[..3].iter().collect::<String>();
+    let end = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("{start}...{end}")
+}
+
+/// Check that the endpoint responds. Any HTTP response counts, since most endpoints return an
+/// error for a bare request.
+fn reachability(host: &HostDefinition) -> String {
+    let client = match host.http.build_client(&host.endpoint) {
+        Ok(client) => client,
+        Err(e) => return format!("invalid HTTP settings: {}", error_message(&e)),
+    };
+
+    match client.call(client.get(&host.endpoint).timeout(REACHABLE_TIMEOUT)) {
+        Ok(response) => format!("reachable (HTTP {})", response.status()),
+        Err(ureq::Error::Status(status, _)) => format!("reachable (HTTP {status})"),
+        Err(ureq::Error::Transport(e)) => format!("unreachable: {e}"),
+    }
+}
+
+fn default_model_lines(options: &ModelOptions) -> Vec<String> {
+    let model = options.full_model_spec().model_name().to_string();
+    let (host_name, choice) = options.host_choice();
+    let status = match options.api_host() {
+        Ok(host) => match host.list_models(false) {
+            Ok(models) => {
+                let found = models.iter().any(|m| {
+                    m.name == model || m.name.strip_suffix(":latest") == Some(model.as_str())
+                });
+                if found {
+                    "found in the host's model list".to_string()
+                } else {
+                    "not found in the host's model list".to_string()
+                }
+            }
+            Err(e) if matches!(e.current_context(), ModelError::ListModelsUnsupported) => {
+                "not checked, since the host can't list models".to_string()
+            }
+            Err(e) => format!("could not check: {}", error_message(&e)),
+        },
+        Err(e) => format!("could not check: {}", error_message(&e)),
+    };
+
+    vec![
+        format!("Default model: {model} on {host_name} ({choice})"),
+        format!("  {status}"),
+    ]
+}
+
+fn cache_line() -> String {
+    let cache = match Cache::new() {
+        Ok(cache) => cache,
+        Err(e) => return format!("Cache: unavailable: {}", error_message(&e)),
+    };
+
+    let dir = cache.dir().display();
+    let probe = "doctor.json";
+    match cache.write_cache(probe, true) {
+        Ok(()) => {
+            std::fs::remove_file(cache.dir().join(probe)).ok();
+            format!("Cache: {dir} (writable)")
+        }
+        Err(e) => format!("Cache: {dir} is not writable: {}", error_message(&e)),
+    }
+}
+
+fn tokenizer_line(options: &ModelOptions) -> String {
+    let name = options.tokenizer_name();
+    match Tokenizer::from_pretrained(name) {
+        Ok(_) => format!("Tokenizer: {name} (loaded)"),
+        Err(Error::Tokenizer(message)) => format!("Tokenizer: {name} failed to load: {message}"),
+        Err(e) => format!("Tokenizer: {name} failed to load: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{api_key_status, default_model_lines, host_lines, mask_secret};
+    use crate::{
+        hosts::HostDefinition,
+        model::{ModelOptions, ModelSpec},
+        requests::with_replay,
+        tests::base_dir,
+    };
+
+    fn ollama_host() -> HostDefinition {
+        let mut host = HostDefinition::builtin()["ollama"].clone();
+        host.endpoint = "http://ollama.test".to_string();
+        host
+    }
+
+    #[test]
+    fn masked_key() {
+        assert_eq!(mask_secret("sk-abcdefghijklmnop"), "sk-...mnop");
+        assert_eq!(mask_secret("short"), "****");
+
+        let mut host = ollama_host();
+        host.api_key = Some("PROMPTBOX_TEST_DOCTOR_KEY".to_string());
+        assert_eq!(
+            api_key_status(&host),
+            "PROMPTBOX_TEST_DOCTOR_KEY is not set"
+        );
+
+        std::env::set_var("PROMPTBOX_TEST_DOCTOR_KEY", "sk-abcdefghijklmnop");
+        let status = api_key_status(&host);
+        std::env::remove_var("PROMPTBOX_TEST_DOCTOR_KEY");
+        assert_eq!(status, "PROMPTBOX_TEST_DOCTOR_KEY is set (sk-...mnop)");
+        assert!(!status.contains("abcdefghijklmnop"));
+    }
+
+    #[test]
+    fn host_and_default_model() {
+        let mut options = ModelOptions {
+            model: ModelSpec::Plain("llama2".to_string()),
+            ..Default::default()
+        };
+        options.host.insert("local".to_string(), ollama_host());
+        options.default_host = "local".to_string();
+
+        let (host, model) = with_replay(base_dir("recordings/ollama"), || {
+            (
+                host_lines("local", &options.host["local"]),
+                default_model_lines(&options),
+            )
+        });
+
+        assert_eq!(
+            host,
+            vec![
+                "  local: http://ollama.test",
+                "    Endpoint: reachable (HTTP 200)",
+                "    API key: not required",
+            ]
+        );
+        assert_eq!(
+            model,
+            vec![
+                "Default model: llama2 on local (default host)",
+                "  found in the host's model list",
+            ]
+        );
+    }
+}
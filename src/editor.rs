@@ -0,0 +1,57 @@
+use std::{io::Write, process::Command};
+
+use error_stack::{Report, ResultExt};
+
+use crate::error::Error;
+
+/// The editor to use, from `$VISUAL` or `$EDITOR`.
+fn editor_command() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// Open the user's editor on a temporary file containing `initial`, and return the saved contents.
+pub fn edit_text(initial: &str) -> Result<String, Report<Error>> {
+    let mut file = tempfile::Builder::new()
+        .prefix("promptbox-")
+        .suffix(".md")
+        .tempfile()
+        .change_context(Error::Io)
+        .attach_printable("Creating temporary file for editor")?;
+    file.write_all(initial.as_bytes())
+        .and_then(|_| file.flush())
+        .change_context(Error::Io)
+        .attach_printable("Writing temporary file for editor")?;
+
+    // The editor may contain arguments, such as `code --wait`.
+    let editor = editor_command();
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let mut command = Command::new(program);
+    command.args(words).arg(file.path());
+
+    // If stdin was piped into promptbox, give the editor the terminal instead.
+    #[cfg(unix)]
+    if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        if let Ok(tty) = std::fs::File::open("/dev/tty") {
+            command.stdin(tty);
+        }
+    }
+
+    let status = command
+        .status()
+        .change_context(Error::Editor)
+        .attach_printable_lazy(|| format!("Running {editor}"))?;
+
+    if !status.success() {
+        return Err(Report::new(Error::Editor))
+            .attach_printable(format!("{editor} exited with {status}"));
+    }
+
+    std::fs::read_to_string(file.path())
+        .change_context(Error::Io)
+        .attach_printable("Reading temporary file from editor")
+}
@@ -0,0 +1,75 @@
+/// Replace `$NAME` and `${NAME}` in `value` with the value of the environment variable `NAME`.
+/// `$$` produces a literal `$`. Returns the name of the variable if one is not set.
+pub fn expand_env_vars(value: &str) -> Result<String, String> {
+    expand_vars(value, |name| std::env::var(name).ok())
+}
+
+fn expand_vars(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, remaining) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(rest.to_string()),
+            }
+        } else if let Some(remaining) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = remaining;
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        if name.is_empty() {
+            // A lone `$` is left as is.
+            output.push('$');
+        } else {
+            let value = lookup(name).ok_or_else(|| name.to_string())?;
+            output.push_str(&value);
+        }
+        rest = remaining;
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_vars;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "TENANT" => Some("acme".to_string()),
+            "HOME" => Some("/home/me".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand() {
+        assert_eq!(expand_vars("plain", lookup).unwrap(), "plain");
+        assert_eq!(expand_vars("$TENANT", lookup).unwrap(), "acme");
+        assert_eq!(
+            expand_vars("${HOME}/templates-$TENANT.d", lookup).unwrap(),
+            "/home/me/templates-acme.d"
+        );
+        assert_eq!(
+            expand_vars("costs $$5 or $", lookup).unwrap(),
+            "costs $5 or $"
+        );
+    }
+
+    #[test]
+    fn missing_var() {
+        assert_eq!(expand_vars("${NOPE}", lookup).unwrap_err(), "NOPE");
+        assert_eq!(expand_vars("a-$NOPE-b", lookup).unwrap_err(), "NOPE");
+    }
+}
@@ -1,3 +1,5 @@
+use error_stack::{AttachmentKind, FrameKind, Report};
+use itertools::Itertools;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,6 +10,10 @@ pub enum Error {
     MissingField(&'static str),
     #[error("Unknown model host {0}")]
     UnknownModelHost(String),
+    #[error("Unknown profile {0}")]
+    UnknownProfile(String),
+    #[error("Invalid HTTP settings")]
+    HttpSettings,
     #[error("Error reading template")]
     ParseTemplate,
     #[error("Template not found")]
@@ -32,6 +38,34 @@ pub enum Error {
     Cache,
     #[error(transparent)]
     CmdlineParseFailure(#[from] clap::Error),
+    #[error("Failed to run editor")]
+    Editor,
+    #[error("Canceled")]
+    Canceled,
+    #[error("Some tests failed")]
+    TestFailure,
+    #[error("Snapshots do not match")]
+    SnapshotMismatch,
+    #[error("Found problems in templates")]
+    CheckFailed,
+    #[error("Failed to list models from host {0}")]
+    ListModels(String),
     #[error("Failed to encode tokens: {0}")]
     Tokenizer(String),
 }
+
+/// Format an error, including its attached messages, on a single line.
+pub fn error_message<C>(report: &Report<C>) -> String {
+    report
+        .frames()
+        .filter_map(|frame| match frame.kind() {
+            FrameKind::Context(context) => Some(context.to_string()),
+            FrameKind::Attachment(AttachmentKind::Printable(printable)) => {
+                Some(printable.to_string())
+            }
+            FrameKind::Attachment(_) => None,
+        })
+        // Some messages, such as those from clap, contain additional help text after the first line.
+        .filter_map(|message| message.lines().next().map(|line| line.trim().to_string()))
+        .join(": ")
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use etcetera::BaseStrategy;
 use itertools::Itertools;
@@ -13,9 +13,23 @@ pub fn global_config_dirs() -> Vec<PathBuf> {
         .collect::<Vec<_>>()
 }
 
+/// The `.env` files to load: the closest one in `start_dir` or its parents, followed by any in the
+/// global configuration directories.
+pub fn dotenv_files(start_dir: &Path) -> Vec<PathBuf> {
+    let local = start_dir
+        .ancestors()
+        .map(|dir| dir.join(".env"))
+        .find(|path| path.is_file());
+    let global = global_config_dirs()
+        .into_iter()
+        .map(|dir| dir.join(".env"))
+        .filter(|path| path.is_file());
+    local.into_iter().chain(global).collect()
+}
+
 pub fn load_dotenv() {
-    dotenvy::dotenv().ok();
-    for config_dir in global_config_dirs() {
-        dotenvy::from_filename(config_dir.join(".env")).ok();
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    for file in dotenv_files(&current_dir) {
+        dotenvy::from_path(file).ok();
     }
 }
@@ -0,0 +1,217 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::Mutex,
+};
+
+use error_stack::{Report, ResultExt};
+
+use crate::model::ModelError;
+
+/// Keys printed by `api_key_command`, so that each command only runs once.
+static COMMAND_KEYS: Mutex<BTreeMap<Vec<String>, String>> = Mutex::new(BTreeMap::new());
+
+/// Where to find the API key for a host. The key is looked up when a request needs it, so
+/// commands don't run for hosts that are never used.
+#[derive(Clone, Default)]
+pub struct ApiKey {
+    host: String,
+    /// A key given directly, such as with `--api-key`
+    value: Option<String>,
+    /// The environment variable that holds the key
+    env: Option<String>,
+    /// A file containing the key
+    file: Option<PathBuf>,
+    /// A command that prints the key
+    command: Vec<String>,
+}
+
+impl ApiKey {
+    pub fn new(
+        host: &str,
+        env: Option<String>,
+        file: Option<PathBuf>,
+        command: Vec<String>,
+    ) -> Self {
+        Self {
+            host: host.to_string(),
+            value: None,
+            env,
+            file,
+            command,
+        }
+    }
+
+    /// Use this key instead of the configured sources.
+    pub fn with_value(mut self, value: Option<String>) -> Self {
+        self.value = value;
+        self
+    }
+
+    /// Get the key. Sources are checked in order: a key given directly, the environment
+    /// variable, the file, and the command. This returns `None` if the host doesn't have a key
+    /// configured.
+    pub fn get(&self) -> Result<Option<String>, Report<ModelError>> {
+        if let Some(value) = self.value.as_ref() {
+            return Ok(Some(value.clone()));
+        }
+
+        if let Some(value) = self
+            .env
+            .as_ref()
+            .and_then(|var| std::env::var(var).ok())
+            .filter(|value| !value.is_empty())
+        {
+            return Ok(Some(value));
+        }
+
+        if let Some(file) = self.file.as_ref() {
+            let key = std::fs::read_to_string(file)
+                .change_context_lazy(|| ModelError::ApiKey(self.host.clone()))
+                .attach_printable_lazy(|| {
+                    format!("Failed to read API key file {}", file.display())
+                })?;
+            return Ok(Some(key.trim().to_string()));
+        }
+
+        if !self.command.is_empty() {
+            return self.run_command().map(Some);
+        }
+
+        match self.env.as_ref() {
+            Some(var) => Err(Report::new(ModelError::ApiKey(self.host.clone()))
+                .attach_printable(format!("Environment variable {var} is not set"))),
+            None => Ok(None),
+        }
+    }
+
+    fn run_command(&self) -> Result<String, Report<ModelError>> {
+        let mut cache = COMMAND_KEYS.lock().unwrap();
+        if let Some(key) = cache.get(&self.command) {
+            return Ok(key.clone());
+        }
+
+        let command_line = self.command.join(" ");
+        let error = || ModelError::ApiKey(self.host.clone());
+        // Leave stdin and stderr attached to the terminal, since password managers may need to
+        // prompt to unlock.
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .change_context_lazy(error)
+            .attach_printable_lazy(|| format!("Failed to run API key command `{command_line}`"))?;
+
+        if !output.status.success() {
+            return Err(Report::new(error()).attach_printable(format!(
+                "API key command `{command_line}` failed with {}",
+                output.status
+            )));
+        }
+
+        let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if key.is_empty() {
+            return Err(Report::new(error())
+                .attach_printable(format!("API key command `{command_line}` printed no key")));
+        }
+
+        cache.insert(self.command.clone(), key.clone());
+        Ok(key)
+    }
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("host", &self.host)
+            .field("value", &self.value.as_ref().map(|_| "<redacted>"))
+            .field("env", &self.env)
+            .field("file", &self.file)
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApiKey;
+    use crate::{error::error_message, model::ModelError};
+
+    #[test]
+    fn missing_env_var() {
+        let key = ApiKey::new(
+            "test",
+            Some("PROMPTBOX_TEST_MISSING_KEY".to_string()),
+            None,
+            vec![],
+        );
+        let err = key.get().unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::ApiKey(host) if host == "test"));
+        assert!(error_message(&err).contains("PROMPTBOX_TEST_MISSING_KEY"));
+    }
+
+    #[test]
+    fn no_key_configured() {
+        assert_eq!(ApiKey::new("test", None, None, vec![]).get().unwrap(), None);
+    }
+
+    #[test]
+    fn value_takes_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("key");
+        std::fs::write(&file, "from-file\n").unwrap();
+
+        let key = ApiKey::new(
+            "test",
+            Some("PROMPTBOX_TEST_MISSING_KEY".to_string()),
+            Some(file),
+            vec![],
+        );
+        assert_eq!(key.get().unwrap().as_deref(), Some("from-file"));
+
+        let key = key.with_value(Some("override".to_string()));
+        assert_eq!(key.get().unwrap().as_deref(), Some("override"));
+        assert!(!format!("{key:?}").contains("override"));
+    }
+
+    #[test]
+    fn missing_file() {
+        let key = ApiKey::new("test", None, Some("/nonexistent/key".into()), vec![]);
+        let err = key.get().unwrap_err();
+        assert!(error_message(&err).contains("/nonexistent/key"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_runs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let script = format!("echo run >> '{}'; echo ' secret-key '", counter.display());
+        let key = ApiKey::new(
+            "test",
+            None,
+            None,
+            vec!["sh".to_string(), "-c".to_string(), script],
+        );
+
+        assert_eq!(key.get().unwrap().as_deref(), Some("secret-key"));
+        assert_eq!(key.clone().get().unwrap().as_deref(), Some("secret-key"));
+        let runs = std::fs::read_to_string(&counter).unwrap();
+        assert_eq!(runs.lines().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_command() {
+        let key = ApiKey::new(
+            "test",
+            None,
+            None,
+            vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()],
+        );
+        let err = key.get().unwrap_err();
+        assert!(error_message(&err).contains("`sh -c exit 3` failed"));
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+};
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{ModelHost, ModelInput};
+use crate::model::{ModelError, ModelOptions};
+
+/// How the command writes the model's response to stdout.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandOutput {
+    /// The response is written as plain text.
+    #[default]
+    Text,
+    /// Each line is a JSON object such as `{ "delta": "text" }`, or `{ "error": "message" }`.
+    Ndjson,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextLimitSource {
+    /// Run the command with the arguments `context_limit <model>` and read the limit from stdout.
+    Subcommand,
+}
+
+/// The context length limit for models served by a command.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CommandContextLimit {
+    Fixed(usize),
+    Query(ContextLimitSource),
+}
+
+/// Options for the command protocol, which runs a local program for each request.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CommandOptions {
+    /// The program and its arguments
+    #[serde(default)]
+    pub program: Vec<String>,
+    /// The format of the program's output
+    #[serde(default)]
+    pub output: CommandOutput,
+    /// A fixed context length limit, or how to query it from the program
+    pub context_limit: Option<CommandContextLimit>,
+}
+
+#[derive(Debug)]
+pub struct CommandHost {
+    command: Vec<String>,
+    output: CommandOutput,
+    context_limit: Option<CommandContextLimit>,
+}
+
+#[derive(Serialize, Debug)]
+struct CommandRequest<'a> {
+    model: &'a str,
+    host: Option<&'a str>,
+    temperature: f32,
+    format: Option<crate::model::OutputFormat>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    stop: &'a [String],
+    max_tokens: Option<u32>,
+    system: Option<&'a str>,
+    prompt: &'a str,
+    images: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommandDelta {
+    #[serde(default)]
+    delta: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl CommandHost {
+    pub fn new(
+        command: Vec<String>,
+        output: CommandOutput,
+        context_limit: Option<CommandContextLimit>,
+    ) -> Self {
+        Self {
+            command,
+            output,
+            context_limit,
+        }
+    }
+
+    fn build_command(&self) -> Result<Command, Report<ModelError>> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(Report::new(ModelError::Command))
+                .attach_printable("The host has an empty command");
+        };
+
+        let mut command = Command::new(program);
+        command.args(args);
+        Ok(command)
+    }
+
+    fn command_description(&self) -> String {
+        self.command.join(" ")
+    }
+}
+
+impl ModelHost for CommandHost {
+    fn send_model_request(
+        &self,
+        options: &ModelOptions,
+        input: ModelInput,
+        message_tx: flume::Sender<String>,
+    ) -> Result<(), Report<ModelError>> {
+        let spec = options.full_model_spec();
+        let request = CommandRequest {
+            model: spec.model_name(),
+            host: spec.host_name(),
+            temperature: options.temperature,
+            format: options.format,
+            top_p: options.top_p,
+            top_k: options.top_k,
+            frequency_penalty: options.frequency_penalty,
+            presence_penalty: options.presence_penalty,
+            stop: &options.stop,
+            max_tokens: options.max_tokens,
+            system: input.system,
+            prompt: input.prompt,
+            images: input
+                .images
+                .iter()
+                .map(|image| {
+                    json!({
+                        "mimetype": image.mimetype,
+                        "data": image.as_base64(),
+                    })
+                })
+                .collect(),
+        };
+        let body = serde_json::to_vec(&request).change_context(ModelError::FormatPrompt)?;
+
+        let mut child = self
+            .build_command()?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .change_context(ModelError::Command)
+            .attach_printable_lazy(|| self.command_description())?;
+
+        // Write the request from another thread, so that a command which starts writing output
+        // before reading all of its input can't deadlock.
+        let mut stdin = child.stdin.take().expect("stdin was piped");
+        let writer = std::thread::spawn(move || stdin.write_all(&body));
+
+        let stdout = child.stdout.take().expect("stdout was piped");
+        let result = read_output(self.output, BufReader::new(stdout), &message_tx);
+
+        let status = child.wait().change_context(ModelError::Command)?;
+        // The command may exit without reading its input, so a write error is not a problem by
+        // itself.
+        writer.join().ok();
+        result.attach_printable_lazy(|| self.command_description())?;
+
+        if !status.success() {
+            return Err(Report::new(ModelError::Command)).attach_printable(format!(
+                "{} exited with {status}",
+                self.command_description()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
+        match self.context_limit {
+            None => Ok(None),
+            Some(CommandContextLimit::Fixed(limit)) => Ok(Some(limit)),
+            Some(CommandContextLimit::Query(ContextLimitSource::Subcommand)) => {
+                let output = self
+                    .build_command()?
+                    .args(["context_limit", model_name])
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .change_context(ModelError::Command)
+                    .attach_printable_lazy(|| self.command_description())?;
+
+                if !output.status.success() {
+                    return Err(Report::new(ModelError::Command)).attach_printable(format!(
+                        "{} context_limit exited with {}",
+                        self.command_description(),
+                        output.status
+                    ));
+                }
+
+                let limit = String::from_utf8_lossy(&output.stdout);
+                let limit = limit.trim();
+                if limit.is_empty() {
+                    return Ok(None);
+                }
+
+                limit
+                    .parse::<usize>()
+                    .change_context(ModelError::Deserialize)
+                    .attach_printable_lazy(|| format!("Context limit {limit}"))
+                    .map(Some)
+            }
+        }
+    }
+}
+
+/// Read the command's output and send it to `message_tx` as it arrives.
+fn read_output(
+    format: CommandOutput,
+    reader: impl BufRead,
+    message_tx: &flume::Sender<String>,
+) -> Result<(), Report<ModelError>> {
+    match format {
+        CommandOutput::Text => {
+            let mut reader = reader;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let bytes = reader
+                    .read_line(&mut line)
+                    .change_context(ModelError::Raw)?;
+                if bytes == 0 {
+                    break;
+                }
+                message_tx.send(line.clone()).ok();
+            }
+        }
+        CommandOutput::Ndjson => {
+            for line in reader.lines() {
+                let line = line.change_context(ModelError::Raw)?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let delta = serde_json::from_str::<CommandDelta>(&line)
+                    .change_context(ModelError::Deserialize)
+                    .attach_printable_lazy(|| line.clone())?;
+                if let Some(error) = delta.error {
+                    return Err(Report::new(ModelError::Command)).attach_printable(error);
+                }
+                if let Some(delta) = delta.delta {
+                    message_tx.send(delta).ok();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn shell(script: &str) -> Vec<String> {
+        ["sh", "-c", script, "sh"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn send(host: &CommandHost, prompt: &str) -> Result<String, Report<ModelError>> {
+        let (tx, rx) = flume::unbounded();
+        let input = ModelInput {
+            prompt,
+            system: Some("be brief"),
+            images: vec![],
+        };
+        host.send_model_request(&ModelOptions::default(), input, tx)?;
+        Ok(rx.drain().collect())
+    }
+
+    #[test]
+    fn sends_request() {
+        let host = CommandHost::new(shell("cat"), CommandOutput::Text, None);
+        let output = send(&host, "a prompt").expect("running command");
+        let request: serde_json::Value = serde_json::from_str(&output).expect("parsing request");
+        assert_eq!(request["model"], json!("gpt-3.5-turbo"));
+        assert_eq!(request["prompt"], json!("a prompt"));
+        assert_eq!(request["system"], json!("be brief"));
+        assert_eq!(request["images"], json!([]));
+    }
+
+    #[test]
+    fn ndjson_output() {
+        let host = CommandHost::new(
+            shell(r#"cat > /dev/null; printf '{"delta":"hello "}\n\n{"delta":"there"}\n'"#),
+            CommandOutput::Ndjson,
+            None,
+        );
+        assert_eq!(send(&host, "a prompt").unwrap(), "hello there");
+    }
+
+    #[test]
+    fn ndjson_error() {
+        let host = CommandHost::new(
+            shell(r#"cat > /dev/null; echo '{"error":"out of memory"}'"#),
+            CommandOutput::Ndjson,
+            None,
+        );
+        let err = send(&host, "a prompt").unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::Command));
+    }
+
+    #[test]
+    fn failed_command() {
+        let host = CommandHost::new(shell("exit 3"), CommandOutput::Text, None);
+        let err = send(&host, "a prompt").unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::Command));
+    }
+
+    #[test]
+    fn context_limit() {
+        let host = CommandHost::new(
+            shell("exit 1"),
+            CommandOutput::Text,
+            Some(CommandContextLimit::Fixed(1000)),
+        );
+        assert_eq!(host.model_context_limit("model").unwrap(), Some(1000));
+
+        let host = CommandHost::new(
+            shell(r#"if [ "$1" = context_limit ] && [ "$2" = model ]; then echo 8192; fi"#),
+            CommandOutput::Text,
+            Some(CommandContextLimit::Query(ContextLimitSource::Subcommand)),
+        );
+        assert_eq!(host.model_context_limit("model").unwrap(), Some(8192));
+    }
+
+    #[test]
+    fn parse_context_limit() {
+        #[derive(Deserialize)]
+        struct Input {
+            context_limit: CommandContextLimit,
+        }
+
+        let fixed: Input = toml::from_str("context_limit = 4096").unwrap();
+        assert_eq!(fixed.context_limit, CommandContextLimit::Fixed(4096));
+        let query: Input = toml::from_str(r#"context_limit = "subcommand""#).unwrap();
+        assert_eq!(
+            query.context_limit,
+            CommandContextLimit::Query(ContextLimitSource::Subcommand)
+        );
+    }
+}
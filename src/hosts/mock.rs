@@ -0,0 +1,210 @@
+use std::{path::PathBuf, time::Duration};
+
+use error_stack::{Report, ResultExt};
+use serde::Deserialize;
+
+use super::{ModelHost, ModelInput};
+use crate::{
+    model::{map_model_response_err, ModelError, ModelOptions},
+    requests::{retry_request, RetryOptions},
+};
+
+/// Options for the mock protocol, which answers requests locally without calling a model.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MockOptions {
+    /// A TOML file of canned responses. If no response matches, the prompt is echoed back.
+    pub fixtures: Option<PathBuf>,
+    /// Send the response in chunks of this many characters, to simulate streaming.
+    pub chunk_size: Option<usize>,
+    /// Wait this many milliseconds before responding, and between chunks.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Fail requests with this HTTP status code.
+    pub fail_status: Option<u16>,
+    /// Fail this many attempts before succeeding. If omitted, every attempt fails.
+    pub fail_times: Option<u32>,
+    /// The body of failed responses, such as the error message a host sends for a prompt that
+    /// doesn't fit in the model's context.
+    pub fail_message: Option<String>,
+    /// The context length limit to report for models.
+    pub context_limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MockFixtures {
+    #[serde(default)]
+    response: Vec<MockResponse>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MockResponse {
+    /// A regular expression matched against the prompt
+    #[serde(rename = "match")]
+    pattern: String,
+    response: String,
+}
+
+#[derive(Debug)]
+pub struct MockHost {
+    options: MockOptions,
+    retry: RetryOptions,
+}
+
+impl MockHost {
+    pub fn new(options: MockOptions, retry: RetryOptions) -> Self {
+        Self { options, retry }
+    }
+
+    /// Find the response for the prompt, using the first matching fixture.
+    fn response_for(&self, prompt: &str) -> Result<String, Report<ModelError>> {
+        let Some(path) = self.options.fixtures.as_ref() else {
+            return Ok(prompt.to_string());
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .change_context(ModelError::MockFixtures)
+            .attach_printable_lazy(|| path.display().to_string())?;
+        let fixtures: MockFixtures = toml::from_str(&contents)
+            .change_context(ModelError::MockFixtures)
+            .attach_printable_lazy(|| path.display().to_string())?;
+
+        for fixture in fixtures.response {
+            let re = regex::Regex::new(&fixture.pattern)
+                .change_context(ModelError::MockFixtures)
+                .attach_printable_lazy(|| format!("Pattern {}", fixture.pattern))?;
+            if re.is_match(prompt) {
+                return Ok(fixture.response);
+            }
+        }
+
+        Ok(prompt.to_string())
+    }
+
+    fn simulate_latency(&self) {
+        if self.options.latency_ms > 0 {
+            std::thread::sleep(Duration::from_millis(self.options.latency_ms));
+        }
+    }
+}
+
+impl ModelHost for MockHost {
+    fn send_model_request(
+        &self,
+        _options: &ModelOptions,
+        input: ModelInput,
+        message_tx: flume::Sender<String>,
+    ) -> Result<(), Report<ModelError>> {
+        let mut attempts = 0;
+        // The failure is a ureq error, so that it is retried in the same way as a real one.
+        #[allow(clippy::result_large_err)]
+        let simulate_request = || {
+            attempts += 1;
+            self.simulate_latency();
+            match self.options.fail_status {
+                Some(status) if attempts <= self.options.fail_times.unwrap_or(u32::MAX) => {
+                    let message = self
+                        .options
+                        .fail_message
+                        .as_deref()
+                        .unwrap_or("Simulated failure");
+                    let response = ureq::Response::new(status, "Mock Failure", message)?;
+                    Err(ureq::Error::Status(status, response))
+                }
+                _ => Ok(()),
+            }
+        };
+        retry_request(&self.retry, simulate_request).map_err(map_model_response_err)?;
+
+        let response = self.response_for(input.prompt)?;
+        let chars = response.chars().collect::<Vec<_>>();
+        let chunk_size = self.options.chunk_size.unwrap_or(chars.len()).max(1);
+        for (i, chunk) in chars.chunks(chunk_size).enumerate() {
+            if i > 0 {
+                self.simulate_latency();
+            }
+            message_tx.send(chunk.iter().collect()).ok();
+        }
+
+        Ok(())
+    }
+
+    fn model_context_limit(&self, _model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
+        Ok(self.options.context_limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    fn send(options: MockOptions, prompt: &str) -> Result<Vec<String>, Report<ModelError>> {
+        let retry = RetryOptions {
+            base_delay_ms: 10,
+            ..Default::default()
+        };
+        let host = MockHost::new(options, retry);
+        let (tx, rx) = flume::unbounded();
+        let input = ModelInput {
+            prompt,
+            system: None,
+            images: vec![],
+        };
+        host.send_model_request(&ModelOptions::default(), input, tx)?;
+        Ok(rx.drain().collect())
+    }
+
+    #[test]
+    fn echo() {
+        let chunks = send(MockOptions::default(), "hello there").unwrap();
+        assert_eq!(chunks, vec!["hello there"]);
+    }
+
+    #[test]
+    fn chunks() {
+        let options = MockOptions {
+            chunk_size: Some(4),
+            ..Default::default()
+        };
+        let chunks = send(options, "hello there").unwrap();
+        assert_eq!(chunks, vec!["hell", "o th", "ere"]);
+    }
+
+    #[test]
+    fn fixtures() {
+        let options = MockOptions {
+            fixtures: Some(base_dir("mock_fixtures.toml")),
+            ..Default::default()
+        };
+        assert_eq!(
+            send(options.clone(), "Please summarize this").unwrap(),
+            vec!["A short summary."]
+        );
+        assert_eq!(
+            send(options.clone(), "Return JSON").unwrap(),
+            vec![r#"{ "items": [{ "name": "a" }] }"#]
+        );
+        assert_eq!(send(options, "no match").unwrap(), vec!["no match"]);
+    }
+
+    #[test]
+    fn failure() {
+        let options = MockOptions {
+            fail_status: Some(500),
+            ..Default::default()
+        };
+        let err = send(options, "hello").unwrap_err();
+        assert!(matches!(err.current_context(), ModelError::Model(500, _)));
+    }
+
+    #[test]
+    fn retries_rate_limit() {
+        let options = MockOptions {
+            fail_status: Some(429),
+            fail_times: Some(1),
+            ..Default::default()
+        };
+        let chunks = send(options, "hello").unwrap();
+        assert_eq!(chunks, vec!["hello"]);
+    }
+}
@@ -1,15 +1,21 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
-use error_stack::Report;
-use serde::Deserialize;
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
 
+use self::{api_key::ApiKey, command::CommandOptions, mock::MockOptions, openai::AzureOptions};
 use crate::{
     error::Error,
     image::ImageData,
     model::{ModelError, ModelOptions},
     option::{overwrite_from_option, overwrite_option_from_option},
+    provenance::MergedKeys,
+    requests::{HttpOptions, RetryOptions},
 };
 
+pub mod api_key;
+pub mod command;
+pub mod mock;
 pub mod ollama;
 pub mod openai;
 mod together;
@@ -30,6 +36,20 @@ pub trait ModelHost: std::fmt::Debug {
     ) -> Result<(), Report<ModelError>>;
 
     fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>>;
+
+    /// List the models that the host offers. If `refresh` is set, hosts that cache the list
+    /// fetch it again.
+    fn list_models(&self, _refresh: bool) -> Result<Vec<HostModel>, Report<ModelError>> {
+        Err(Report::new(ModelError::ListModelsUnsupported))
+    }
+}
+
+/// A model returned by a host's model listing API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostModel {
+    pub name: String,
+    /// The context length, if the host includes it in the listing
+    pub context_length: Option<usize>,
 }
 
 /// An API definition to talk to a host send prompts to it.
@@ -39,7 +59,14 @@ pub enum HostProtocol {
     Ollama,
     #[serde(rename = "openai")]
     OpenAi,
+    /// OpenAI models hosted on Azure
+    #[serde(rename = "azure_openai")]
+    AzureOpenAi,
     Together,
+    /// Answers requests locally, for testing
+    Mock,
+    /// Runs a local command for each request
+    Command,
 }
 
 impl HostProtocol {
@@ -49,9 +76,17 @@ impl HostProtocol {
             // There's no API for getting the context length here. For real OpenAI we set this to
             // true though.
             HostProtocol::OpenAi => false,
+            HostProtocol::AzureOpenAi => true,
             HostProtocol::Together => true,
+            HostProtocol::Mock => true,
+            HostProtocol::Command => true,
         }
     }
+
+    /// Whether the host keeps its own cache of the model list.
+    pub fn caches_model_list(&self) -> bool {
+        matches!(self, HostProtocol::Together)
+    }
 }
 
 /// An LLM host
@@ -62,37 +97,124 @@ pub struct HostDefinition {
     pub limit_context_length: bool,
     /// The environment variable that holds the authentication token for this host
     pub api_key: Option<String>,
+    /// A file that contains the authentication token
+    pub api_key_file: Option<PathBuf>,
+    /// A command that prints the authentication token
+    pub api_key_command: Vec<String>,
     /// If true, send "promptbox" in the `user` field of the request. Some hosts
     /// reject the request if this field exists, so it can be disabled by setting this
     /// to false.
     pub send_app_id: bool,
+    /// Options for the mock protocol
+    pub mock: MockOptions,
+    /// Options for the command protocol
+    pub command: CommandOptions,
+    /// Options for the Azure OpenAI protocol
+    pub azure: AzureOptions,
+    /// How to retry failed requests
+    pub retry: RetryOptions,
+    /// Timeouts, proxy, and TLS settings
+    #[serde(flatten)]
+    pub http: HttpOptions,
 }
 
 impl HostDefinition {
-    /// Create a ModelHost from this HostDefinition
-    pub fn into_model_host(&self) -> Box<dyn ModelHost> {
-        let key = self
-            .api_key
-            .as_ref()
-            .and_then(|var_name| std::env::var(var_name).ok());
+    /// Create a ModelHost from this HostDefinition, using `api_key` instead of the configured key
+    /// if it is set. This fails if the HTTP settings are invalid.
+    pub fn into_model_host(
+        &self,
+        name: &str,
+        api_key: Option<&str>,
+    ) -> Result<Box<dyn ModelHost>, Report<Error>> {
+        let key = ApiKey::new(
+            name,
+            self.api_key.clone(),
+            self.api_key_file.clone(),
+            self.api_key_command.clone(),
+        )
+        .with_value(api_key.map(String::from));
         let endpoint = self.endpoint.clone();
-        match self.protocol {
-            HostProtocol::Ollama => Box::new(ollama::OllamaHost::new(Some(endpoint), key)),
+        let client = || {
+            self.http
+                .build_client(&self.endpoint)
+                .attach_printable_lazy(|| format!("Host {name}"))
+        };
+        let host: Box<dyn ModelHost> = match self.protocol {
+            HostProtocol::Ollama => Box::new(ollama::OllamaHost::new(
+                Some(endpoint),
+                key,
+                self.retry.clone(),
+                client()?,
+            )),
             HostProtocol::OpenAi => Box::new(openai::OpenAiHost::new(
                 Some(endpoint),
                 key,
-                self.limit_context_length,
                 self.send_app_id,
+                self.retry.clone(),
+                client()?,
             )),
-            HostProtocol::Together => Box::new(together::TogetherHost::new(endpoint, key)),
-        }
+            HostProtocol::AzureOpenAi => Box::new(
+                openai::OpenAiHost::new(
+                    Some(endpoint),
+                    key,
+                    self.send_app_id,
+                    self.retry.clone(),
+                    client()?,
+                )
+                .with_azure(self.azure.clone()),
+            ),
+            HostProtocol::Together => Box::new(together::TogetherHost::new(
+                endpoint,
+                key,
+                self.retry.clone(),
+                client()?,
+            )),
+            HostProtocol::Mock => {
+                Box::new(mock::MockHost::new(self.mock.clone(), self.retry.clone()))
+            }
+            HostProtocol::Command => Box::new(command::CommandHost::new(
+                self.command.program.clone(),
+                self.command.output,
+                self.command.context_limit,
+            )),
+        };
+
+        Ok(host)
     }
 
     pub fn merge_from_input(&mut self, other: &HostDefinitionInput) {
         overwrite_from_option(&mut self.endpoint, &other.endpoint);
         overwrite_from_option(&mut self.protocol, &other.protocol);
         overwrite_option_from_option(&mut self.api_key, &other.api_key);
+        overwrite_option_from_option(&mut self.api_key_file, &other.api_key_file);
+        overwrite_from_option(&mut self.api_key_command, &other.api_key_command);
         overwrite_from_option(&mut self.limit_context_length, &other.limit_context_length);
+        overwrite_from_option(&mut self.mock, &other.mock);
+        overwrite_from_option(&mut self.command, &other.command);
+        overwrite_from_option(&mut self.azure, &other.azure);
+        overwrite_from_option(&mut self.retry, &other.retry);
+        self.http.merge_from(&other.http);
+    }
+
+    /// Apply the `PROMPTBOX_HOST_<NAME>_ENDPOINT` and `PROMPTBOX_HOST_<NAME>_API_KEY` environment
+    /// variables to this host. Returns the field, variable, and new value for each one that was set.
+    pub fn update_from_env(&mut self, name: &str) -> Vec<(&'static str, String, String)> {
+        let prefix = host_env_prefix(name);
+        let mut updated = Vec::new();
+
+        let endpoint_var = format!("{prefix}_ENDPOINT");
+        if let Ok(endpoint) = std::env::var(&endpoint_var) {
+            self.endpoint = endpoint.clone();
+            updated.push(("endpoint", endpoint_var, endpoint));
+        }
+
+        let key_var = format!("{prefix}_API_KEY");
+        if std::env::var_os(&key_var).is_some() {
+            self.api_key = Some(key_var.clone());
+            updated.push(("api_key", key_var.clone(), key_var));
+        }
+
+        updated
     }
 
     pub fn default_host() -> &'static str {
@@ -106,40 +228,40 @@ impl HostDefinition {
                 "anyscale".to_string(),
                 HostDefinition {
                     endpoint: "https://api.endpoints.anyscale.com/v1".to_string(),
-                    protocol: HostProtocol::OpenAi,
-                    limit_context_length: false,
                     api_key: Some("ANYSCALE_API_KEY".to_string()),
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
             (
                 "deepinfra".to_string(),
                 HostDefinition {
                     endpoint: "https://api.deepinfra.com/v1/openai".to_string(),
-                    protocol: HostProtocol::OpenAi,
-                    limit_context_length: false,
                     api_key: Some("DEEPINFRA_API_KEY".to_string()),
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
             (
                 "fireworks".to_string(),
                 HostDefinition {
                     endpoint: "https://api.fireworks.ai/inference/v1".to_string(),
-                    protocol: HostProtocol::OpenAi,
-                    limit_context_length: false,
                     api_key: Some("FIREWORKS_API_KEY".to_string()),
                     send_app_id: false,
+                    ..Default::default()
                 },
             ),
             (
                 "lm-studio".to_string(),
                 HostDefinition {
                     endpoint: "http://localhost:1234".to_string(),
-                    protocol: HostProtocol::OpenAi,
-                    limit_context_length: false,
-                    api_key: None,
-                    send_app_id: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "mock".to_string(),
+                HostDefinition {
+                    protocol: HostProtocol::Mock,
+                    send_app_id: false,
+                    ..Default::default()
                 },
             ),
             (
@@ -148,28 +270,24 @@ impl HostDefinition {
                     endpoint: ollama::DEFAULT_HOST.to_string(),
                     protocol: HostProtocol::Ollama,
                     limit_context_length: true,
-                    api_key: None,
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
             (
                 "openai".to_string(),
                 HostDefinition {
                     endpoint: openai::OPENAI_HOST.to_string(),
-                    protocol: HostProtocol::OpenAi,
                     limit_context_length: true,
                     api_key: Some("OPENAI_API_KEY".to_string()),
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
             (
                 "openrouter".to_string(),
                 HostDefinition {
                     endpoint: "https://openrouter.ai/api".to_string(),
-                    protocol: HostProtocol::OpenAi,
-                    limit_context_length: false,
                     api_key: Some("OPENROUTER_API_KEY".to_string()),
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
             (
@@ -179,7 +297,7 @@ impl HostDefinition {
                     protocol: HostProtocol::Together,
                     limit_context_length: true,
                     api_key: Some("TOGETHER_API_KEY".to_string()),
-                    send_app_id: true,
+                    ..Default::default()
                 },
             ),
         ]
@@ -188,14 +306,61 @@ impl HostDefinition {
     }
 }
 
+/// An OpenAI-compatible host with no endpoint. Built-in and test hosts override the fields they
+/// need.
+impl Default for HostDefinition {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            protocol: HostProtocol::OpenAi,
+            limit_context_length: false,
+            api_key: None,
+            api_key_file: None,
+            api_key_command: Vec::new(),
+            send_app_id: true,
+            mock: MockOptions::default(),
+            command: CommandOptions::default(),
+            azure: AzureOptions::default(),
+            retry: RetryOptions::default(),
+            http: HttpOptions::default(),
+        }
+    }
+}
+
+/// The prefix of the environment variables for a host, e.g. `PROMPTBOX_HOST_LM_STUDIO` for
+/// `lm-studio`.
+fn host_env_prefix(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("PROMPTBOX_HOST_{name}")
+}
+
 impl TryFrom<HostDefinitionInput> for HostDefinition {
     type Error = Error;
 
     /// Create a HostDefinition from a HostDefinitionInput. If there is an existing HostDefinition
     /// with the same name, use [merge_from_input] instead.
     fn try_from(value: HostDefinitionInput) -> Result<Self, Self::Error> {
-        let endpoint = value.endpoint.ok_or(Error::MissingField("endpoint"))?;
         let protocol = value.protocol.ok_or(Error::MissingField("protocol"))?;
+        // The mock and command protocols don't make any requests, so they don't need an endpoint.
+        let endpoint = match (value.endpoint, &protocol) {
+            (Some(endpoint), _) => endpoint,
+            (None, HostProtocol::Mock | HostProtocol::Command) => String::new(),
+            (None, _) => return Err(Error::MissingField("endpoint")),
+        };
+
+        let command = value.command.unwrap_or_default();
+        if matches!(protocol, HostProtocol::Command) && command.program.is_empty() {
+            return Err(Error::MissingField("command.program"));
+        }
         Ok(Self {
             endpoint,
             limit_context_length: value
@@ -203,7 +368,14 @@ impl TryFrom<HostDefinitionInput> for HostDefinition {
                 .unwrap_or_else(|| protocol.default_context_length_option()),
             protocol,
             api_key: value.api_key,
+            api_key_file: value.api_key_file,
+            api_key_command: value.api_key_command.unwrap_or_default(),
             send_app_id: value.send_app_id.unwrap_or(true),
+            mock: value.mock.unwrap_or_default(),
+            command,
+            azure: value.azure.unwrap_or_default(),
+            retry: value.retry.unwrap_or_default(),
+            http: value.http,
         })
     }
 }
@@ -212,28 +384,178 @@ impl TryFrom<HostDefinitionInput> for HostDefinition {
 pub struct HostDefinitionInput {
     pub endpoint: Option<String>,
     pub api_key: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_command: Option<Vec<String>>,
     pub protocol: Option<HostProtocol>,
     pub limit_context_length: Option<bool>,
     pub send_app_id: Option<bool>,
+    pub mock: Option<MockOptions>,
+    pub command: Option<CommandOptions>,
+    pub azure: Option<AzureOptions>,
+    pub retry: Option<RetryOptions>,
+    #[serde(flatten)]
+    pub http: HttpOptions,
 }
 
 impl HostDefinitionInput {
-    pub fn merge_from_input(&mut self, other: &HostDefinitionInput) {
-        overwrite_option_from_option(&mut self.endpoint, &other.endpoint);
-        overwrite_option_from_option(&mut self.protocol, &other.protocol);
-        overwrite_option_from_option(&mut self.api_key, &other.api_key);
-        overwrite_option_from_option(&mut self.limit_context_length, &other.limit_context_length);
-        overwrite_option_from_option(&mut self.send_app_id, &other.send_app_id);
+    /// Overwrite the values in this host with the values set in `other`. Returns the keys of the
+    /// values that were taken from `other`.
+    pub fn merge_from_input(&mut self, other: &HostDefinitionInput) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        merged.overwrite_option_from_option("endpoint", &mut self.endpoint, &other.endpoint);
+        merged.overwrite_option_from_option("protocol", &mut self.protocol, &other.protocol);
+        merged.overwrite_option_from_option("api_key", &mut self.api_key, &other.api_key);
+        merged.overwrite_option_from_option(
+            "api_key_file",
+            &mut self.api_key_file,
+            &other.api_key_file,
+        );
+        merged.overwrite_option_from_option(
+            "api_key_command",
+            &mut self.api_key_command,
+            &other.api_key_command,
+        );
+        merged.overwrite_option_from_option(
+            "limit_context_length",
+            &mut self.limit_context_length,
+            &other.limit_context_length,
+        );
+        merged.overwrite_option_from_option(
+            "send_app_id",
+            &mut self.send_app_id,
+            &other.send_app_id,
+        );
+        merged.overwrite_option_from_option("mock", &mut self.mock, &other.mock);
+        merged.overwrite_option_from_option("command", &mut self.command, &other.command);
+        merged.overwrite_option_from_option("azure", &mut self.azure, &other.azure);
+        merged.overwrite_option_from_option("retry", &mut self.retry, &other.retry);
+        // The HTTP options are flattened into the host's table.
+        merged.extend(self.http.merge_from(&other.http));
+        merged
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::hosts::HostDefinition;
+    use std::path::PathBuf;
+
+    use super::{command::CommandOutput, host_env_prefix, openai};
+    use crate::{
+        error::Error,
+        hosts::{HostDefinition, HostDefinitionInput},
+    };
 
     #[test]
     fn default_host_is_valid() {
         let builtin = super::HostDefinition::builtin();
         assert!(builtin.contains_key(HostDefinition::default_host()));
     }
+
+    #[test]
+    fn env_overrides() {
+        assert_eq!(host_env_prefix("lm-studio"), "PROMPTBOX_HOST_LM_STUDIO");
+
+        std::env::set_var(
+            "PROMPTBOX_HOST_ENV_TEST_ENDPOINT",
+            "http://ollama.internal:11434",
+        );
+        std::env::set_var("PROMPTBOX_HOST_ENV_TEST_API_KEY", "secret");
+        let mut host = HostDefinition::builtin().remove("ollama").unwrap();
+        host.update_from_env("env.test");
+        assert_eq!(host.endpoint, "http://ollama.internal:11434");
+        assert_eq!(
+            host.api_key.as_deref(),
+            Some("PROMPTBOX_HOST_ENV_TEST_API_KEY")
+        );
+
+        let mut host = HostDefinition::builtin().remove("openai").unwrap();
+        host.update_from_env("unset-host");
+        assert_eq!(host.endpoint, openai::OPENAI_HOST);
+        assert_eq!(host.api_key.as_deref(), Some("OPENAI_API_KEY"));
+    }
+
+    #[test]
+    fn http_options() {
+        let input: HostDefinitionInput = toml::from_str(
+            r#"
+            read_timeout = 600
+            proxy = "http://proxy.example.com:8080"
+            ca_cert = "/etc/ssl/corp.pem"
+            headers = { "OpenAI-Project" = "${PROJECT_ID}", "X-Title" = "PromptBox" }
+            query = { tenant = "acme" }
+            "#,
+        )
+        .unwrap();
+
+        let mut openai = HostDefinition::builtin().remove("openai").unwrap();
+        openai.http.connect_timeout = Some(5);
+        openai
+            .http
+            .headers
+            .insert("OpenAI-Organization".to_string(), "org-1".to_string());
+        openai
+            .http
+            .headers
+            .insert("OpenAI-Project".to_string(), "proj-1".to_string());
+        openai.merge_from_input(&input);
+
+        assert_eq!(
+            openai.http.headers,
+            [
+                ("OpenAI-Organization", "org-1"),
+                ("OpenAI-Project", "${PROJECT_ID}"),
+                ("X-Title", "PromptBox"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+        );
+        assert_eq!(
+            openai.http.query.get("tenant").map(String::as_str),
+            Some("acme")
+        );
+
+        assert_eq!(openai.http.connect_timeout, Some(5));
+        assert_eq!(openai.http.read_timeout, Some(600));
+        assert_eq!(openai.http.total_timeout, None);
+        assert_eq!(
+            openai.http.proxy.as_deref(),
+            Some("http://proxy.example.com:8080")
+        );
+        assert_eq!(
+            openai.http.ca_cert,
+            Some(PathBuf::from("/etc/ssl/corp.pem"))
+        );
+    }
+
+    #[test]
+    fn protocol_tables() {
+        let input: HostDefinitionInput = toml::from_str(
+            r#"
+            protocol = "azure_openai"
+            endpoint = "https://example.openai.azure.com"
+            [azure.deployments]
+            gpt-4 = "gpt4-prod"
+            "#,
+        )
+        .unwrap();
+        let host = HostDefinition::try_from(input).unwrap();
+        assert_eq!(host.azure.api_version, openai::AZURE_API_VERSION);
+        assert_eq!(host.azure.deployments["gpt-4"], "gpt4-prod");
+
+        let input: HostDefinitionInput = toml::from_str(
+            r#"
+            protocol = "command"
+            command = { program = ["./run.sh"], output = "ndjson" }
+            "#,
+        )
+        .unwrap();
+        let host = HostDefinition::try_from(input).unwrap();
+        assert_eq!(host.command.program, vec!["./run.sh"]);
+        assert_eq!(host.command.output, CommandOutput::Ndjson);
+
+        let input: HostDefinitionInput = toml::from_str(r#"protocol = "command""#).unwrap();
+        let err = HostDefinition::try_from(input).unwrap_err();
+        assert!(matches!(err, Error::MissingField("command.program")));
+    }
 }
@@ -6,8 +6,11 @@ use serde_json::json;
 use tracing::{event, instrument, Level};
 use ureq::Response;
 
-use super::{ModelHost, ModelInput};
-use crate::model::{map_model_response_err, ModelError, ModelOptions, OutputFormat};
+use super::{api_key::ApiKey, HostModel, ModelHost, ModelInput};
+use crate::{
+    model::{map_model_response_err, ModelError, ModelOptions, OutputFormat},
+    requests::{add_bearer_token, call_with_retry, request_with_retry, HttpClient, RetryOptions},
+};
 
 pub const DEFAULT_HOST: &str = "http://localhost:11434";
 
@@ -16,12 +19,24 @@ pub struct OllamaHost {
     pub host: Option<String>,
     // Ollama doesn't use an API key, but if someone puts it behind a reverse proxy this could be
     // useful.
-    pub api_key: Option<String>,
+    pub api_key: ApiKey,
+    pub retry: RetryOptions,
+    client: HttpClient,
 }
 
 impl OllamaHost {
-    pub fn new(host: Option<String>, api_key: Option<String>) -> Self {
-        Self { host, api_key }
+    pub fn new(
+        host: Option<String>,
+        api_key: ApiKey,
+        retry: RetryOptions,
+        client: HttpClient,
+    ) -> Self {
+        Self {
+            host,
+            api_key,
+            retry,
+            client,
+        }
     }
 
     fn host(&self) -> &str {
@@ -39,12 +54,7 @@ impl ModelHost for OllamaHost {
     ) -> Result<(), Report<ModelError>> {
         let url = format!("{}/api/generate", self.host());
 
-        let request = ureq::post(&url);
-        let request = if let Some(key) = self.api_key.as_ref() {
-            request.set("Authorization", &format!("Bearer {}", key))
-        } else {
-            request
-        };
+        let request = add_bearer_token(self.client.post(&url), &self.api_key.get()?);
 
         let images = input
             .images
@@ -72,8 +82,7 @@ impl ModelHost for OllamaHost {
 
         event!(Level::INFO, body = ?body, "Sending request");
 
-        let response: Response = request
-            .send_json(body)
+        let response: Response = request_with_retry(&self.client, request, body, &self.retry)
             .map_err(map_model_response_err)
             .attach_printable(url)?;
 
@@ -90,14 +99,18 @@ impl ModelHost for OllamaHost {
 
     fn model_context_limit(&self, model: &str) -> Result<Option<usize>, Report<ModelError>> {
         let url = format!("{}/api/show", self.host());
-        let response: ModelInfo = ureq::post(&url)
-            .send_json(json!({
+        let response: ModelInfo = request_with_retry(
+            &self.client,
+            self.client.post(&url),
+            json!({
                 "name": model
-            }))
-            .map_err(map_model_response_err)
-            .attach_printable(url)?
-            .into_json()
-            .change_context(ModelError::Deserialize)?;
+            }),
+            &self.retry,
+        )
+        .map_err(map_model_response_err)
+        .attach_printable(url)?
+        .into_json()
+        .change_context(ModelError::Deserialize)?;
 
         let context_param = response
             .parameters
@@ -117,6 +130,27 @@ impl ModelHost for OllamaHost {
 
         Ok(Some(context_size))
     }
+
+    fn list_models(&self, _refresh: bool) -> Result<Vec<HostModel>, Report<ModelError>> {
+        let url = format!("{}/api/tags", self.host());
+        let request = add_bearer_token(self.client.get(&url), &self.api_key.get()?);
+        let response: OllamaTags = call_with_retry(&self.client, request, &self.retry)
+            .map_err(map_model_response_err)
+            .attach_printable(url)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        // Getting the context length requires a request per model, so leave it to the registry.
+        let models = response
+            .models
+            .into_iter()
+            .map(|model| HostModel {
+                name: model.name,
+                context_length: None,
+            })
+            .collect();
+        Ok(models)
+    }
 }
 #[derive(Debug, Serialize)]
 pub struct OllamaRequest<'a> {
@@ -147,6 +181,16 @@ struct OllamaResponse {
     // TODO Add response stats
 }
 
+#[derive(Deserialize, Debug)]
+struct OllamaTags {
+    models: Vec<OllamaTag>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaTag {
+    name: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct ModelInfo {
     modelfile: String,
@@ -154,6 +198,65 @@ struct ModelInfo {
     template: String,
 }
 
+#[cfg(test)]
+mod replay_test {
+    use super::{ApiKey, OllamaHost};
+    use crate::{
+        hosts::{ModelHost, ModelInput},
+        model::ModelOptions,
+        requests::{with_replay, HttpClient, RetryOptions},
+        tests::base_dir,
+    };
+
+    fn host() -> OllamaHost {
+        OllamaHost::new(
+            Some("http://ollama.test".to_string()),
+            ApiKey::default(),
+            RetryOptions::default(),
+            HttpClient::default(),
+        )
+    }
+
+    #[test]
+    fn streamed_response() {
+        let options = ModelOptions {
+            model: "llama2".to_string().into(),
+            ..Default::default()
+        };
+        let input = ModelInput {
+            prompt: "Count to three",
+            system: None,
+            images: vec![],
+        };
+
+        let (tx, rx) = flume::unbounded();
+        with_replay(base_dir("recordings/ollama"), || {
+            host().send_model_request(&options, input, tx)
+        })
+        .expect("sending request");
+        let chunks = rx.drain().collect::<Vec<_>>();
+        assert_eq!(chunks, vec!["One", ", two", ", three.", ""]);
+    }
+
+    #[test]
+    fn context_limit_from_modelfile() {
+        let limit = with_replay(base_dir("recordings/ollama"), || {
+            host().model_context_limit("yarn-mistral:7b-128k")
+        })
+        .expect("fetching context limit");
+        assert_eq!(limit, Some(131072));
+    }
+
+    #[test]
+    fn default_context_limit() {
+        let limit = with_replay(base_dir("recordings/ollama"), || {
+            host().model_context_limit("mistral:7b")
+        })
+        .expect("fetching context limit");
+        assert_eq!(limit, Some(2048));
+    }
+}
+
 #[cfg(all(test, feature = "test-ollama"))]
 mod test {
     // Note that for these tests to work, you must be running ollama and already have pulled the models
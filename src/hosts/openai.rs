@@ -1,55 +1,111 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
 
 use error_stack::{Report, ResultExt};
 use serde::Deserialize;
 use serde_json::json;
 
-use super::{ModelHost, ModelInput};
+use super::{api_key::ApiKey, HostModel, ModelHost, ModelInput};
 use crate::{
     model::{map_model_response_err, ModelError, ModelOptions},
-    requests::request_with_retry,
+    requests::{add_bearer_token, call_with_retry, request_with_retry, HttpClient, RetryOptions},
 };
 
 pub const OPENAI_HOST: &str = "https://api.openai.com/v1";
+pub const AZURE_API_VERSION: &str = "2024-02-01";
+
+/// Settings for talking to Azure OpenAI, which puts the deployment name in the URL path and
+/// authenticates with an `api-key` header.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AzureOptions {
+    /// The `api-version` query parameter
+    #[serde(default = "default_azure_api_version")]
+    pub api_version: String,
+    /// Map model names to deployment names. Models that are not in the map use the model name as
+    /// the deployment name.
+    #[serde(default)]
+    pub deployments: BTreeMap<String, String>,
+}
+
+fn default_azure_api_version() -> String {
+    AZURE_API_VERSION.to_string()
+}
+
+impl Default for AzureOptions {
+    fn default() -> Self {
+        Self {
+            api_version: default_azure_api_version(),
+            deployments: BTreeMap::new(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct OpenAiHost {
-    pub api_key: Option<String>,
+    pub api_key: ApiKey,
     pub host: Option<String>,
-    /// Whether or not to check and enforce a context length limit. Usually this is true, but some
-    /// hosts don't provide context length limit information or otherwise manage it themselves.
-    pub do_context_limit: bool,
     pub send_user: bool,
+    pub retry: RetryOptions,
+    /// If set, send requests in the Azure OpenAI format.
+    pub azure: Option<AzureOptions>,
+    client: HttpClient,
 }
 
 impl OpenAiHost {
     pub fn new(
         host: Option<String>,
-        api_key: Option<String>,
-        do_context_limit: bool,
+        api_key: ApiKey,
         send_user: bool,
+        retry: RetryOptions,
+        client: HttpClient,
     ) -> Self {
         Self {
             api_key,
             host,
-            do_context_limit,
             send_user,
+            retry,
+            azure: None,
+            client,
         }
     }
 
+    pub fn with_azure(mut self, azure: AzureOptions) -> Self {
+        self.azure = Some(azure);
+        self
+    }
+
     fn host(&self) -> &str {
         self.host.as_deref().unwrap_or(OPENAI_HOST)
     }
 
-    fn create_base_request(&self, path: &str) -> ureq::Request {
-        let url = format!("{}/{path}", self.host());
+    fn create_base_request(
+        &self,
+        path: &str,
+        model_name: &str,
+    ) -> Result<ureq::Request, Report<ModelError>> {
+        let key = self.api_key.get()?;
+        let Some(azure) = self.azure.as_ref() else {
+            let url = format!("{}/{path}", self.host());
+            return Ok(add_bearer_token(self.client.post(&url), &key));
+        };
 
-        let request = ureq::post(&url);
-        if let Some(key) = self.api_key.as_ref() {
-            request.set("Authorization", &format!("Bearer {}", key))
+        let deployment = azure
+            .deployments
+            .get(model_name)
+            .map(String::as_str)
+            .unwrap_or(model_name);
+        let url = format!(
+            "{}/openai/deployments/{deployment}/{path}",
+            self.host().trim_end_matches('/')
+        );
+        let request = self
+            .client
+            .post(&url)
+            .query("api-version", &azure.api_version);
+        Ok(if let Some(key) = key {
+            request.set("api-key", &key)
         } else {
             request
-        }
+        })
     }
 }
 
@@ -100,8 +156,9 @@ impl ModelHost for OpenAiHost {
             ])
         };
 
+        let model_name = options.full_model_spec().model_name().to_string();
         let mut body = json!({
-            "model": options.full_model_spec().model_name(),
+            "model": model_name,
             "temperature": options.temperature,
             "messages": messages
         });
@@ -135,9 +192,10 @@ impl ModelHost for OpenAiHost {
         }
 
         let mut response: ChatCompletion = request_with_retry(
-            self.create_base_request("chat/completions")
-                .timeout(Duration::from_secs(30)),
+            &self.client,
+            self.create_base_request("chat/completions", &model_name)?,
             body,
+            &self.retry,
         )
         .map_err(map_model_response_err)?
         .into_json()
@@ -154,15 +212,59 @@ impl ModelHost for OpenAiHost {
         Ok(())
     }
 
-    fn model_context_limit(&self, model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
-        if self.do_context_limit {
-            Ok(Some(model_context_limit(model_name)))
-        } else {
-            Ok(None)
+    /// The OpenAI API doesn't provide context lengths, so these come from the model registry
+    /// instead.
+    fn model_context_limit(&self, _model_name: &str) -> Result<Option<usize>, Report<ModelError>> {
+        Ok(None)
+    }
+
+    fn list_models(&self, _refresh: bool) -> Result<Vec<HostModel>, Report<ModelError>> {
+        // Azure lists every model that the resource could deploy, so list the configured
+        // deployments instead.
+        if let Some(azure) = self.azure.as_ref() {
+            let models = azure
+                .deployments
+                .keys()
+                .map(|name| HostModel {
+                    name: name.clone(),
+                    context_length: None,
+                })
+                .collect();
+            return Ok(models);
         }
+
+        let url = format!("{}/models", self.host());
+        let request = add_bearer_token(self.client.get(&url), &self.api_key.get()?);
+        let response: ModelList = call_with_retry(&self.client, request, &self.retry)
+            .map_err(map_model_response_err)
+            .attach_printable(url)?
+            .into_json()
+            .change_context(ModelError::Deserialize)?;
+
+        let models = response
+            .data
+            .into_iter()
+            .map(|model| HostModel {
+                name: model.id,
+                context_length: model.context_length,
+            })
+            .collect();
+        Ok(models)
     }
 }
 
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListedModel {
+    id: String,
+    /// OpenRouter and some other OpenAI-compatible hosts include the context length.
+    context_length: Option<usize>,
+}
+
 #[derive(Debug, Deserialize)]
 struct ChatCompletionMessage {
     role: String,
@@ -203,41 +305,110 @@ fn send_completion_request(options: &ModelOptions, prompt: &str) -> Result<(), u
     //     .into_json()?;
 }
 
-fn model_context_limit(model_name: &str) -> usize {
-    // This will all have to be updated once the preview models are productionized.
-    if model_name.starts_with("gpt-4") {
-        if model_name.starts_with("gpt-4-32k") {
-            32768
-        } else if model_name.ends_with("preview") {
-            128000
-        } else {
-            8192
+#[cfg(test)]
+mod test {
+    use super::{ApiKey, AzureOptions, OpenAiHost, AZURE_API_VERSION};
+    use crate::{
+        hosts::{ModelHost, ModelInput},
+        model::{ModelError, ModelOptions},
+        requests::{with_replay, HttpClient, RetryOptions},
+        tests::base_dir,
+    };
+
+    fn test_host(endpoint: &str) -> OpenAiHost {
+        OpenAiHost::new(
+            Some(endpoint.to_string()),
+            ApiKey::default().with_value(Some("test-key".to_string())),
+            true,
+            RetryOptions::default(),
+            HttpClient::default(),
+        )
+    }
+
+    fn send(prompt: &str) -> Result<String, error_stack::Report<ModelError>> {
+        send_to(
+            &test_host("http://openai.test/v1"),
+            "gpt-3.5-turbo",
+            "recordings/openai",
+            prompt,
+        )
+    }
+
+    fn send_to(
+        host: &OpenAiHost,
+        model: &str,
+        recordings: &str,
+        prompt: &str,
+    ) -> Result<String, error_stack::Report<ModelError>> {
+        let options = ModelOptions {
+            model: model.to_string().into(),
+            ..Default::default()
+        };
+        let input = ModelInput {
+            prompt,
+            system: Some("Be brief."),
+            images: vec![],
+        };
+
+        let (tx, rx) = flume::unbounded();
+        with_replay(base_dir(recordings), || {
+            host.send_model_request(&options, input, tx)
+        })?;
+        Ok(rx.drain().collect())
+    }
+
+    #[test]
+    fn chat_completion() {
+        assert_eq!(send("Say hello").unwrap(), "Hello there!");
+    }
+
+    #[test]
+    fn chat_completion_error() {
+        let err = send("Trigger an error").unwrap_err();
+        match err.current_context() {
+            ModelError::Model(400, message) => assert!(message.contains("invalid_request_error")),
+            e => panic!("Unexpected error {e:?}"),
         }
-    } else if model_name.contains("-16k") || model_name == "gpt-3.5-turbo-1106" {
-        // gpt-3.5-turbo will also be 16385 on December 11, 2023
-        16385
-    } else {
-        4096
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::model_context_limit;
+    #[test]
+    fn azure_deployment() {
+        let host = test_host("http://azure.test/").with_azure(AzureOptions {
+            api_version: AZURE_API_VERSION.to_string(),
+            deployments: [("gpt-4".to_string(), "gpt4-prod".to_string())]
+                .into_iter()
+                .collect(),
+        });
+        assert_eq!(
+            send_to(&host, "gpt-4", "recordings/azure", "Say hello").unwrap(),
+            "Hello from Azure!"
+        );
+
+        let request = host
+            .create_base_request("chat/completions", "gpt-4")
+            .unwrap();
+        assert_eq!(request.header("api-key"), Some("test-key"));
+        assert_eq!(request.header("Authorization"), None);
+    }
 
-    /// Check against a bunch of real models to make sure the logic is right
     #[test]
-    fn model_context_values() {
-        assert_eq!(model_context_limit("gpt-3.5-turbo"), 4096);
-        assert_eq!(model_context_limit("gpt-3.5-turbo-16k"), 16385);
-        assert_eq!(model_context_limit("gpt-3.5-turbo-1106"), 16385);
-        assert_eq!(model_context_limit("gpt-3.5-turbo-instruct"), 4096);
-        assert_eq!(model_context_limit("gpt-3.5-turbo-0613"), 4096);
-        assert_eq!(model_context_limit("gpt-4-1106-preview"), 128000);
-        assert_eq!(model_context_limit("gpt-4-vision-preview"), 128000);
-        assert_eq!(model_context_limit("gpt-4"), 8192);
-        assert_eq!(model_context_limit("gpt-4-0613"), 8192);
-        assert_eq!(model_context_limit("gpt-4-32k"), 32768);
-        assert_eq!(model_context_limit("gpt-4-32k-0613"), 32768);
+    fn list_models() {
+        let models = with_replay(base_dir("recordings/openai"), || {
+            test_host("http://openai.test/v1").list_models(false)
+        })
+        .unwrap();
+        let names = models.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["gpt-4o", "gpt-3.5-turbo"]);
+
+        // Azure lists the configured deployments without a request.
+        let host = test_host("http://azure.test/").with_azure(AzureOptions {
+            api_version: AZURE_API_VERSION.to_string(),
+            deployments: [("gpt-4".to_string(), "gpt4-prod".to_string())]
+                .into_iter()
+                .collect(),
+        });
+        let models = host.list_models(false).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "gpt-4");
     }
 }
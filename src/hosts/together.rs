@@ -4,12 +4,12 @@ use error_stack::{Report, ResultExt};
 use serde::{Deserialize, Serialize};
 use tracing::{event, instrument, Level};
 
-use super::{ModelHost, ModelInput};
+use super::{api_key::ApiKey, HostModel, ModelHost, ModelInput};
 use crate::{
     cache::Cache,
     chat_template::{apply_chat_template, builtin_chat_template, ChatTemplate},
     model::{map_model_response_err, ModelError, ModelOptions, OutputFormat},
-    requests::{add_bearer_token, request_with_retry},
+    requests::{add_bearer_token, call_with_retry, request_with_retry, HttpClient, RetryOptions},
 };
 
 pub const DEFAULT_HOST: &str = "https://api.together.xyz";
@@ -17,7 +17,9 @@ pub const DEFAULT_HOST: &str = "https://api.together.xyz";
 #[derive(Debug)]
 pub struct TogetherHost {
     pub host: String,
-    pub api_key: Option<String>,
+    pub api_key: ApiKey,
+    pub retry: RetryOptions,
+    client: HttpClient,
 
     cache: Option<Cache>,
 
@@ -25,10 +27,12 @@ pub struct TogetherHost {
 }
 
 impl TogetherHost {
-    pub fn new(host: String, api_key: Option<String>) -> Self {
+    pub fn new(host: String, api_key: ApiKey, retry: RetryOptions, client: HttpClient) -> Self {
         Self {
             host,
             api_key,
+            retry,
+            client,
             cache: Cache::new().ok(),
             model_info: OnceCell::new(),
         }
@@ -40,20 +44,22 @@ impl TogetherHost {
 
     fn fetch_all_model_info(&self) -> Result<Vec<ModelInfo>, Report<ModelError>> {
         let url = format!("{}/models/info", self.host());
-        add_bearer_token(ureq::get(&url), &self.api_key)
-            .call()
+        let request = add_bearer_token(self.client.get(&url), &self.api_key.get()?);
+        call_with_retry(&self.client, request, &self.retry)
             .map_err(map_model_response_err)
             .attach_printable(url)?
             .into_json::<Vec<ModelInfo>>()
             .change_context(ModelError::Deserialize)
     }
 
-    fn get_all_model_info(&self) -> Result<&[ModelInfo], Report<ModelError>> {
+    /// Get the model info from memory, the cache, or the host. If `refresh` is set, skip the
+    /// cache and fetch it from the host.
+    fn get_all_model_info(&self, refresh: bool) -> Result<&[ModelInfo], Report<ModelError>> {
         if let Some(model_info) = self.model_info.get() {
             return Ok(model_info);
         }
 
-        if let Some(cache) = self.cache.as_ref() {
+        if let Some(cache) = self.cache.as_ref().filter(|_| !refresh) {
             let model_info: Option<Vec<ModelInfo>> = cache
                 .read_cache(
                     "together_model_info.json",
@@ -80,7 +86,7 @@ impl TogetherHost {
     }
 
     fn get_model_info(&self, model: &str) -> Result<&ModelInfo, Report<ModelError>> {
-        let info = self.get_all_model_info()?;
+        let info = self.get_all_model_info(false)?;
         let model_info = info
             .iter()
             .find(|i| i.name == model)
@@ -191,8 +197,8 @@ impl ModelHost for TogetherHost {
         event!(Level::INFO, prompt = %prompt, body=?body, "Sending request");
 
         let url = format!("{}/inference", self.host());
-        let request = add_bearer_token(ureq::post(&url), &self.api_key);
-        let mut response = request_with_retry(request, body)
+        let request = add_bearer_token(self.client.post(&url), &self.api_key.get()?);
+        let mut response = request_with_retry(&self.client, request, body, &self.retry)
             .map_err(map_model_response_err)
             .attach_printable_lazy(|| url.clone())?
             .into_json::<TogetherResponse>()
@@ -217,6 +223,18 @@ impl ModelHost for TogetherHost {
         let context_size = model_info.context_length.unwrap_or(2048);
         Ok(Some(context_size as usize))
     }
+
+    fn list_models(&self, refresh: bool) -> Result<Vec<HostModel>, Report<ModelError>> {
+        let models = self
+            .get_all_model_info(refresh)?
+            .iter()
+            .map(|info| HostModel {
+                name: info.name.clone(),
+                context_length: info.context_length.map(|c| c as usize),
+            })
+            .collect();
+        Ok(models)
+    }
 }
 #[derive(Debug, Serialize)]
 struct TogetherRequest<'a> {
@@ -274,6 +292,123 @@ struct ModelConfig {
     stop: Option<Vec<String>>,
 }
 
+#[cfg(test)]
+mod replay_test {
+    use std::cell::OnceCell;
+
+    use super::{ApiKey, ModelConfig, ModelInfo, TogetherHost};
+    use crate::{
+        cache::Cache,
+        hosts::{ModelHost, ModelInput},
+        model::{ModelError, ModelOptions},
+        requests::{with_replay, HttpClient, RetryOptions},
+        tests::base_dir,
+    };
+
+    fn host() -> TogetherHost {
+        // Skip the cache so that the model info comes from the recording.
+        TogetherHost {
+            host: "http://together.test".to_string(),
+            api_key: ApiKey::default().with_value(Some("test-key".to_string())),
+            retry: RetryOptions::default(),
+            client: HttpClient::default(),
+            cache: None,
+            model_info: OnceCell::new(),
+        }
+    }
+
+    #[test]
+    fn context_limit_from_model_info() {
+        let host = host();
+        with_replay(base_dir("recordings/together"), || {
+            assert_eq!(
+                host.model_context_limit("mistralai/Mixtral-8x7B-Instruct-v0.1")
+                    .unwrap(),
+                Some(32768)
+            );
+            assert_eq!(
+                host.model_context_limit("togethercomputer/llama-2-7b")
+                    .unwrap(),
+                Some(2048)
+            );
+            let err = host.model_context_limit("nonexistent").unwrap_err();
+            assert!(matches!(
+                err.current_context(),
+                ModelError::ModelNotFound(model) if model == "nonexistent"
+            ));
+        });
+    }
+
+    #[test]
+    fn list_models_from_model_info() {
+        let models = with_replay(base_dir("recordings/together"), || {
+            host().list_models(false)
+        })
+        .unwrap();
+        let mixtral = models
+            .iter()
+            .find(|m| m.name == "mistralai/Mixtral-8x7B-Instruct-v0.1")
+            .unwrap();
+        assert_eq!(mixtral.context_length, Some(32768));
+    }
+
+    #[test]
+    fn refresh_skips_cached_model_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::with_dir(dir.path().to_path_buf());
+        let cached = vec![ModelInfo {
+            context_length: Some(1024),
+            name: "cached-model".to_string(),
+            config: ModelConfig::default(),
+        }];
+        cache
+            .write_cache("together_model_info.json", &cached)
+            .unwrap();
+
+        let cached_host = TogetherHost {
+            cache: Some(Cache::with_dir(dir.path().to_path_buf())),
+            ..host()
+        };
+        let models = cached_host.list_models(false).unwrap();
+        assert_eq!(
+            models.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(),
+            vec!["cached-model"]
+        );
+
+        let refreshed_host = TogetherHost {
+            cache: Some(cache),
+            ..host()
+        };
+        let models = with_replay(base_dir("recordings/together"), || {
+            refreshed_host.list_models(true)
+        })
+        .unwrap();
+        assert!(models
+            .iter()
+            .any(|m| m.name == "mistralai/Mixtral-8x7B-Instruct-v0.1"));
+    }
+
+    #[test]
+    fn inference_with_prompt_format() {
+        let options = ModelOptions {
+            model: "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string().into(),
+            ..Default::default()
+        };
+        let input = ModelInput {
+            prompt: "Say hello",
+            system: Some("Be brief."),
+            images: vec![],
+        };
+
+        let (tx, rx) = flume::unbounded();
+        with_replay(base_dir("recordings/together"), || {
+            host().send_model_request(&options, input, tx)
+        })
+        .expect("sending request");
+        assert_eq!(rx.drain().collect::<String>(), "Hello!");
+    }
+}
+
 #[cfg(all(test, feature = "test-together"))]
 mod test {
     use super::model_context_limit;
@@ -5,6 +5,7 @@ use error_stack::{Report, ResultExt};
 
 use crate::error::Error;
 
+#[derive(Clone)]
 pub struct ImageData {
     pub mimetype: String,
     pub contents: Vec<u8>,
@@ -21,6 +22,11 @@ impl std::fmt::Debug for ImageData {
 impl ImageData {
     pub fn new(filename: &Path) -> Result<Self, Report<Error>> {
         let contents = std::fs::read(filename).change_context(Error::Image)?;
+        Self::from_bytes(contents)
+    }
+
+    /// Create an image from raw data, detecting the type of the image.
+    pub fn from_bytes(contents: Vec<u8>) -> Result<Self, Report<Error>> {
         let info = imageinfo::ImageInfo::from_raw_data(&contents).change_context(Error::Image)?;
 
         Ok(ImageData {
@@ -0,0 +1,183 @@
+use std::{collections::HashMap, path::Path};
+
+use error_stack::{Report, ResultExt};
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use crate::{
+    editor::edit_text,
+    error::Error,
+    template::{OptionType, PromptOption},
+};
+
+/// Line editor helper that completes file paths when enabled.
+struct PromptHelper {
+    paths: Option<FilenameCompleter>,
+}
+
+impl Completer for PromptHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        match self.paths.as_ref() {
+            Some(paths) => paths.complete(line, pos, ctx),
+            None => Ok((pos, vec![])),
+        }
+    }
+}
+
+impl Hinter for PromptHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PromptHelper {}
+impl Validator for PromptHelper {}
+impl Helper for PromptHelper {}
+
+/// Ask the user for values for the given options, which were not supplied in any other way.
+/// Only required options are asked for, so none of them are flags or have defaults. The returned
+/// values are strings, suitable for parsing as command-line arguments.
+pub fn prompt_for_options(
+    base_dir: &Path,
+    options: &[(&String, &PromptOption)],
+) -> Result<HashMap<String, Vec<String>>, Report<Error>> {
+    let mut editor = Editor::<PromptHelper, DefaultHistory>::new()
+        .change_context(Error::Io)
+        .attach_printable("Initializing line editor")?;
+    editor.set_helper(Some(PromptHelper { paths: None }));
+
+    eprintln!("This template requires some options which were not provided.");
+
+    let mut values = HashMap::new();
+    for (name, option) in options {
+        if let Some(helper) = editor.helper_mut() {
+            helper.paths = option.option_type.is_path().then(FilenameCompleter::new);
+        }
+
+        if option.description.is_empty() {
+            eprintln!("\n{name}");
+        } else {
+            eprintln!("\n{name}: {}", option.description);
+        }
+
+        if option.option_type == OptionType::String {
+            eprintln!("  Press enter on an empty line to write the value in your editor.");
+        }
+
+        if option.array {
+            eprintln!("  Enter one value per line, and an empty line when done.");
+        }
+
+        let mut option_values = vec![];
+        loop {
+            let prompt = if option.array {
+                format!("{name}[{}]> ", option_values.len())
+            } else {
+                format!("{name}> ")
+            };
+
+            let line = match editor.readline(&prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                    return Err(Report::new(Error::Canceled));
+                }
+                Err(e) => {
+                    return Err(e)
+                        .change_context(Error::Io)
+                        .attach_printable("Reading input")
+                }
+            };
+
+            let value = line.trim();
+            if value.is_empty() {
+                if option.array && !option_values.is_empty() {
+                    break;
+                }
+
+                if option.option_type == OptionType::String {
+                    let value = edit_text("")?;
+                    let value = value.trim();
+                    if !value.is_empty() {
+                        option_values.push(value.to_string());
+                        if !option.array {
+                            break;
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            match validate_value(base_dir, option.option_type, value) {
+                Ok(()) => {
+                    option_values.push(value.to_string());
+                    if !option.array {
+                        break;
+                    }
+                }
+                Err(message) => eprintln!("  {message}"),
+            }
+        }
+
+        values.insert(name.to_string(), option_values);
+    }
+
+    Ok(values)
+}
+
+fn validate_value(base_dir: &Path, option_type: OptionType, value: &str) -> Result<(), String> {
+    match option_type {
+        OptionType::String => Ok(()),
+        OptionType::Integer => value
+            .parse::<i64>()
+            .map(|_| ())
+            .map_err(|_| "Enter a whole number".to_string()),
+        OptionType::Number => value
+            .parse::<f32>()
+            .map(|_| ())
+            .map_err(|_| "Enter a number".to_string()),
+        _ if option_type.is_path() => {
+            if base_dir.join(value).is_file() {
+                Ok(())
+            } else {
+                Err(format!("{value} is not a file"))
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate_value;
+    use crate::{template::OptionType, tests::base_dir};
+
+    #[test]
+    fn validate_numbers() {
+        let dir = base_dir("");
+        assert!(validate_value(&dir, OptionType::Integer, "5").is_ok());
+        assert!(validate_value(&dir, OptionType::Integer, "5.5").is_err());
+        assert!(validate_value(&dir, OptionType::Number, "5.5").is_ok());
+        assert!(validate_value(&dir, OptionType::Number, "abc").is_err());
+    }
+
+    #[test]
+    fn validate_path() {
+        let dir = base_dir("");
+        assert!(validate_value(&dir, OptionType::File, "test1.txt").is_ok());
+        assert!(validate_value(&dir, OptionType::Csv, "data/people.csv").is_ok());
+        assert!(validate_value(&dir, OptionType::Image, "doesntexist.jpg").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, io::Write, path::PathBuf, time::Duration};
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::ModelsArgs,
+    cache::Cache,
+    config::Config,
+    error::error_message,
+    error::Error,
+    hosts::HostModel,
+    model::{ModelError, ModelOptions, ModelSpec},
+    model_info::ModelPrice,
+};
+
+/// How long to use a host's cached model list before fetching it again.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A model offered by a host, with the configured information about it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ListedModel {
+    pub host: String,
+    pub name: String,
+    pub context_length: Option<usize>,
+    /// The price from the model registry
+    pub price: Option<ModelPrice>,
+    /// Configured aliases that refer to this model
+    pub aliases: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedModels {
+    /// The endpoint the list came from, so that changing the endpoint fetches a new list.
+    endpoint: String,
+    models: Vec<HostModel>,
+}
+
+/// Print the models available from the configured hosts.
+pub fn run_list_models(
+    base_dir: PathBuf,
+    args: ModelsArgs,
+    output: &mut impl Write,
+) -> Result<(), Report<Error>> {
+    let config = Config::from_directory_with_profile(base_dir, args.profile.profile.as_deref())?;
+    let cache = Cache::new().ok();
+    let models = list_models(&config.model, &args.host, cache.as_ref(), args.refresh)?;
+
+    if args.json {
+        serde_json::to_writer_pretty(&mut *output, &models).change_context(Error::Io)?;
+        writeln!(output).change_context(Error::Io)?;
+    } else {
+        for line in format_table(&models) {
+            writeln!(output, "{line}").change_context(Error::Io)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// List the models from the given hosts, or from every host if `hosts` is empty. When listing
+/// every host, hosts that fail are reported and skipped, and hosts that can't list models are
+/// skipped silently.
+pub fn list_models(
+    options: &ModelOptions,
+    hosts: &[String],
+    cache: Option<&Cache>,
+    refresh: bool,
+) -> Result<Vec<ListedModel>, Report<Error>> {
+    let host_names = if hosts.is_empty() {
+        options.host.keys().sorted().cloned().collect()
+    } else {
+        if let Some(unknown) = hosts.iter().find(|h| !options.host.contains_key(*h)) {
+            return Err(Report::new(Error::UnknownModelHost(unknown.clone())));
+        }
+        hosts.to_vec()
+    };
+
+    let aliases = alias_targets(options);
+    let mut listed = Vec::new();
+    for host_name in host_names {
+        let models = match host_models(options, &host_name, cache, refresh) {
+            Ok(models) => models,
+            Err(e) if hosts.is_empty() => {
+                if !matches!(
+                    e.downcast_ref::<ModelError>(),
+                    Some(ModelError::ListModelsUnsupported)
+                ) {
+                    eprintln!("{}", error_message(&e));
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        for model in models.into_iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+            let info = options.models.get(&model.name);
+            let context_length = model
+                .context_length
+                .or_else(|| info.and_then(|info| info.context_length));
+
+            // Ollama uses the `latest` tag when a model name has no tag.
+            let model_aliases = std::iter::once(model.name.as_str())
+                .chain(model.name.strip_suffix(":latest"))
+                .filter_map(|name| aliases.get(&(host_name.clone(), name.to_string())))
+                .flatten()
+                .cloned()
+                .collect();
+
+            listed.push(ListedModel {
+                host: host_name.clone(),
+                name: model.name,
+                context_length,
+                price: info.and_then(|info| info.price),
+                aliases: model_aliases,
+            });
+        }
+    }
+
+    Ok(listed)
+}
+
+/// Get a host's models from the cache, or from the host if the cache is missing or stale. Hosts
+/// that cache their own model list skip this cache.
+fn host_models(
+    options: &ModelOptions,
+    host_name: &str,
+    cache: Option<&Cache>,
+    refresh: bool,
+) -> Result<Vec<HostModel>, Report<Error>> {
+    let definition = &options.host[host_name];
+    let cache = cache.filter(|_| !definition.protocol.caches_model_list());
+    let filename = format!("models-{}.json", cache_name(host_name));
+
+    if !refresh {
+        let cached = cache
+            .and_then(|cache| {
+                cache
+                    .read_cache::<CachedModels>(&filename, CACHE_TTL)
+                    .ok()
+                    .flatten()
+            })
+            .filter(|cached| cached.endpoint == definition.endpoint);
+        if let Some(cached) = cached {
+            return Ok(cached.models);
+        }
+    }
+
+    let host = definition.into_model_host(host_name, None)?;
+    let models = host
+        .list_models(refresh)
+        .change_context_lazy(|| Error::ListModels(host_name.to_string()))?;
+
+    if let Some(cache) = cache {
+        let cached = CachedModels {
+            endpoint: definition.endpoint.clone(),
+            models,
+        };
+        cache.write_cache(&filename, &cached).ok();
+        return Ok(cached.models);
+    }
+
+    Ok(models)
+}
+
+/// A version of the host name that is safe to use in a file name
+fn cache_name(host_name: &str) -> String {
+    host_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Map each host and model name to the aliases that resolve to it.
+fn alias_targets(options: &ModelOptions) -> HashMap<(String, String), Vec<String>> {
+    let mut targets: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for alias in options.alias.keys().sorted() {
+        let alias_options = ModelOptions {
+            model: ModelSpec::Plain(alias.clone()),
+            ..options.clone()
+        };
+        let model = alias_options.full_model_spec().model_name().to_string();
+        targets
+            .entry((alias_options.host_name(), model))
+            .or_default()
+            .push(alias.clone());
+    }
+
+    targets
+}
+
+fn format_table(models: &[ListedModel]) -> Vec<String> {
+    let header = ["HOST", "MODEL", "CONTEXT", "PRICE/1M", "ALIASES"].map(String::from);
+    let rows = std::iter::once(header)
+        .chain(models.iter().map(|model| {
+            [
+                model.host.clone(),
+                model.name.clone(),
+                model
+                    .context_length
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                model
+                    .price
+                    .map(|p| format!("${}/${}", p.input, p.output))
+                    .unwrap_or_else(|| "-".to_string()),
+                model.aliases.join(", "),
+            ]
+        }))
+        .collect::<Vec<_>>();
+
+    let width = |column: usize| rows.iter().map(|row| row[column].len()).max().unwrap_or(0);
+    let (host_width, model_width, context_width, price_width) =
+        (width(0), width(1), width(2), width(3));
+
+    rows.iter()
+        .map(|[host, model, context, price, aliases]| {
+            format!(
+                "{host:host_width$}  {model:model_width$}  {context:context_width$}  \
+                {price:price_width$}  {aliases}"
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{format_table, list_models, ListedModel};
+    use crate::{
+        cache::Cache,
+        error::Error,
+        hosts::HostDefinition,
+        model::{ModelOptions, ModelSpec},
+        model_info::{ModelInfo, ModelPrice, ModelRegistry},
+        requests::with_replay,
+        tests::base_dir,
+    };
+
+    fn options() -> ModelOptions {
+        let mut host = HostDefinition::builtin()["ollama"].clone();
+        host.endpoint = "http://ollama.test".to_string();
+
+        let mut options = ModelOptions::default();
+        options.host.insert("local".to_string(), host);
+        options.default_host = "local".to_string();
+        options
+            .alias
+            .insert("llama".to_string(), ModelSpec::Plain("llama2".to_string()));
+        options.alias.insert(
+            "remote-llama".to_string(),
+            ModelSpec::Full {
+                model: "llama2".to_string(),
+                host: Some("together".to_string()),
+            },
+        );
+        options.models = ModelRegistry::new(HashMap::from([(
+            "llama2".to_string(),
+            ModelInfo {
+                context_length: Some(4096),
+                price: Some(ModelPrice {
+                    input: 0.2,
+                    output: 0.25,
+                }),
+                ..Default::default()
+            },
+        )]));
+        options
+    }
+
+    fn list(cache: Option<&Cache>) -> Vec<ListedModel> {
+        with_replay(base_dir("recordings/ollama"), || {
+            list_models(&options(), &["local".to_string()], cache, false).unwrap()
+        })
+    }
+
+    #[test]
+    fn list_with_aliases() {
+        let models = list(None);
+        assert_eq!(
+            models,
+            vec![
+                ListedModel {
+                    host: "local".to_string(),
+                    name: "llama2:latest".to_string(),
+                    context_length: Some(4096),
+                    price: Some(ModelPrice {
+                        input: 0.2,
+                        output: 0.25,
+                    }),
+                    aliases: vec!["llama".to_string()],
+                },
+                ListedModel {
+                    host: "local".to_string(),
+                    name: "mistral:7b".to_string(),
+                    context_length: None,
+                    price: None,
+                    aliases: vec![],
+                },
+            ]
+        );
+
+        assert_eq!(
+            format_table(&models),
+            vec![
+                "HOST   MODEL          CONTEXT  PRICE/1M    ALIASES",
+                "local  llama2:latest  4096     $0.2/$0.25  llama",
+                "local  mistral:7b     -        -",
+            ]
+        );
+    }
+
+    #[test]
+    fn cached_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::with_dir(dir.path().to_path_buf());
+        let fetched = list(Some(&cache));
+
+        // Without a replay directory this would fail to reach the host, so it must use the cache.
+        let cached = list_models(&options(), &["local".to_string()], Some(&cache), false).unwrap();
+        assert_eq!(cached, fetched);
+    }
+
+    #[test]
+    fn unknown_host() {
+        let err = list_models(&options(), &["nonexistent".to_string()], None, false).unwrap_err();
+        assert!(matches!(
+            err.current_context(),
+            Error::UnknownModelHost(host) if host == "nonexistent"
+        ));
+    }
+}
@@ -1,38 +1,166 @@
 use std::{ffi::OsString, path::PathBuf};
 
-use args::{parse_main_args, parse_template_args, FoundCommand, GlobalRunArgs};
+use args::{
+    parse_main_args, parse_template_args, ConfigCommand, FoundCommand, GlobalRunArgs, MainCommand,
+};
 use config::Config;
 use error::Error;
 use error_stack::{Report, ResultExt};
 use global_config::load_dotenv;
 use hosts::ModelInput;
 use image::ImageData;
-use model::ModelOptions;
+use model::{ModelError, ModelOptions};
 use template::{assemble_template, render_template, ParsedTemplate};
+use vars::TemplateVars;
 
 mod args;
 mod cache;
 mod chat_template;
+mod check;
 mod config;
+mod config_command;
 mod context;
+mod doctor;
+mod editor;
+mod env;
 mod error;
 mod global_config;
 mod hosts;
 mod image;
+mod interactive;
+mod list_models;
 mod model;
+mod model_info;
 mod option;
+mod provenance;
 mod requests;
+mod route;
+mod show;
+mod snapshot;
 mod template;
+mod testing;
 #[cfg(test)]
 mod tests;
 mod tracing;
+mod vars;
 
+type GeneratedTemplate = (GlobalRunArgs, ModelOptions, String, String, Vec<ImageData>);
+
+/// A rendered template, before the prompt has been trimmed to fit a model's context.
+struct PreparedTemplate {
+    args: GlobalRunArgs,
+    model_options: ModelOptions,
+    template_path: PathBuf,
+    template: String,
+    template_context: tera::Context,
+    prompt: String,
+    system: String,
+    images: Vec<ImageData>,
+}
+
+impl PreparedTemplate {
+    /// Trim the prompt to fit the context of the model in `model_options`.
+    fn fit_prompt(&self, model_options: &ModelOptions) -> Result<String, Report<Error>> {
+        context::enforce_context_limit(
+            model_options,
+            &self.template_path,
+            &self.template,
+            self.template_context.clone(),
+            self.prompt.clone(),
+        )
+    }
+
+    /// Send the prompt to each model in the fallback chain until one succeeds, forwarding its
+    /// output to `message_tx`. `first_prompt` is the prompt already trimmed for the first model,
+    /// if there is one.
+    fn send(
+        &self,
+        mut first_prompt: Option<String>,
+        message_tx: &flume::Sender<String>,
+    ) -> Result<(), Report<Error>> {
+        let models = self.model_options.model_chain();
+        let mut result = Ok(());
+        for (i, model_options) in models.iter().enumerate() {
+            let is_last = i + 1 == models.len();
+            let model_name = model_description(model_options);
+
+            let prompt = match first_prompt.take() {
+                Some(prompt) => Ok(prompt),
+                None => self.fit_prompt(model_options),
+            };
+            let prompt = match prompt {
+                Ok(prompt) => prompt,
+                Err(e) if !is_last && fit_failure_should_fall_back(&e) => {
+                    eprintln!("{model_name} failed: {}", error::error_message(&e));
+                    continue;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            if i > 0 && self.args.verbose {
+                eprintln!("== Prompt for {model_name}:\n{prompt}\n");
+            }
+
+            let (sent_output, sent) = send_prompt(
+                model_options,
+                &prompt,
+                &self.system,
+                self.images.clone(),
+                message_tx,
+            );
+            match sent {
+                Ok(()) => {
+                    if models.len() > 1 {
+                        eprintln!("Response from {model_name}");
+                    }
+                    break;
+                }
+                Err(e) if !is_last && !sent_output && e.current_context().should_fall_back() => {
+                    eprintln!("{model_name} failed: {}", error::error_message(&e));
+                }
+                Err(e) => {
+                    result = Err(e.change_context(Error::RunPrompt));
+                    break;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Trim the prompt for the first model, returning the pieces of the generated template.
+    fn into_generated(self) -> Result<GeneratedTemplate, Report<Error>> {
+        let prompt = self.fit_prompt(&self.model_options)?;
+        Ok((
+            self.args,
+            self.model_options,
+            prompt,
+            self.system,
+            self.images,
+        ))
+    }
+}
+
+#[cfg(test)]
 fn generate_template(
     base_dir: PathBuf,
     template: String,
     cmdline: Vec<OsString>,
-) -> Result<(GlobalRunArgs, ModelOptions, String, String, Vec<ImageData>), Report<Error>> {
-    let config = Config::from_directory(base_dir.clone())?;
+) -> Result<GeneratedTemplate, Report<Error>> {
+    prepare_template(base_dir, template, cmdline, None)?.into_generated()
+}
+
+/// Render the template and its system prompt, without trimming the prompt to fit the context.
+fn prepare_template(
+    base_dir: PathBuf,
+    template: String,
+    cmdline: Vec<OsString>,
+    vars: Option<TemplateVars>,
+) -> Result<PreparedTemplate, Report<Error>> {
+    let mut config = Config::from_directory(base_dir.clone())?;
 
     let ParsedTemplate {
         template,
@@ -42,11 +170,23 @@ fn generate_template(
         ..
     } = config.find_template(&template)?;
 
-    let (mut args, mut template_context, images) = parse_template_args(cmdline, &base_dir, &input)?;
+    let (mut args, mut template_context, images) =
+        parse_template_args(cmdline, &base_dir, &input, vars)?;
+
+    if args.verbose {
+        for warning in &config.warnings {
+            eprintln!("Warning: {warning}");
+        }
+    }
+
+    if let Some(profile) = args.profile.profile.as_deref() {
+        config.apply_profile(profile)?;
+    }
 
     let mut model_options = config.model;
     model_options.update_from_model_input(&input.model);
     model_options.update_from_args(&args);
+    model_options.update_host_endpoints(&args.host_endpoint)?;
 
     let template = assemble_template(&mut args, &mut template_context, template)?;
 
@@ -64,15 +204,50 @@ fn generate_template(
         String::new()
     };
 
-    let prompt = context::enforce_context_limit(
-        &model_options,
-        &template_path,
-        &template,
+    Ok(PreparedTemplate {
+        args,
+        model_options,
+        template_path,
+        template,
         template_context,
         prompt,
-    )?;
+        system: system_prompt,
+        images,
+    })
+}
 
-    Ok((args, model_options, prompt, system_prompt, images))
+/// Send the prompt to a model, forwarding its output to `message_tx`. Returns whether any output
+/// was sent, since it is too late to try another model once output has been written.
+fn send_prompt(
+    model_options: &ModelOptions,
+    prompt: &str,
+    system: &str,
+    images: Vec<ImageData>,
+    message_tx: &flume::Sender<String>,
+) -> (bool, Result<(), Report<ModelError>>) {
+    let (attempt_tx, attempt_rx) = flume::bounded(32);
+    std::thread::scope(|scope| {
+        let request = scope.spawn(move || {
+            let host = model_options.api_host().map_err(|e| {
+                Report::new(ModelError::Raw).attach_printable(error::error_message(&e))
+            })?;
+            let input = ModelInput {
+                prompt,
+                system: (!system.is_empty()).then_some(system),
+                images,
+            };
+            model_options.check_capabilities(&input)?;
+            host.send_model_request(model_options, input, attempt_tx)
+        });
+
+        let mut sent_output = false;
+        for message in attempt_rx {
+            sent_output |= !message.is_empty();
+            message_tx.send(message).ok();
+        }
+
+        (sent_output, request.join().unwrap())
+    })
 }
 
 fn run_template(
@@ -81,18 +256,31 @@ fn run_template(
     args: Vec<OsString>,
     mut output: impl std::io::Write + Send + 'static,
 ) -> Result<(), Report<Error>> {
-    let (args, model_options, prompt, system, images) =
-        generate_template(base_dir, template, args)?;
+    let prepared = prepare_template(base_dir, template, args, None)?;
+    let args = &prepared.args;
+    let system = &prepared.system;
+    let models = prepared.model_options.model_chain();
 
     if args.verbose {
-        eprintln!("{model_options:?}");
+        eprintln!("{:?}", prepared.model_options);
+        for model in &models {
+            let (host, choice) = model.host_choice();
+            eprintln!(
+                "Model {} on {host}: {choice}",
+                model.full_model_spec().model_name()
+            );
+        }
     }
 
+    // The prompt is trimmed separately for each model, since their context limits may differ.
+    let mut first_prompt = None;
     if args.print_prompt || args.verbose || args.dry_run {
+        let prompt = prepared.fit_prompt(&models[0])?;
         if !system.is_empty() {
             eprintln!("== System:\n{system}\n");
         }
         eprintln!("== Prompt:\n{prompt}\n\n== Result:");
+        first_prompt = Some(prompt);
     }
 
     if args.dry_run {
@@ -110,38 +298,95 @@ fn run_template(
         Ok::<(), std::io::Error>(())
     });
 
-    let system = if system.is_empty() {
-        None
-    } else {
-        Some(system)
-    };
+    let result = prepared.send(first_prompt, &message_tx);
 
-    let host = model_options.api_host()?;
-    let input = ModelInput {
-        prompt: &prompt,
-        system: system.as_deref(),
-        images,
-    };
+    drop(message_tx);
+    print_thread.join().unwrap().ok();
+
+    result
+}
 
-    host.send_model_request(&model_options, input, message_tx)
-        .change_context(Error::RunPrompt)?;
+/// Whether a failure to fit the prompt to a model might not happen with the next model: the
+/// model's host could not be found or reached, or its tokenizer could not be loaded. Other
+/// failures, such as template errors, would happen for every model.
+fn fit_failure_should_fall_back(report: &Report<Error>) -> bool {
+    report.frames().any(|frame| {
+        if let Some(e) = frame.downcast_ref::<ModelError>() {
+            return e.should_fall_back();
+        }
 
-    print_thread.join().unwrap().ok();
+        matches!(
+            frame.downcast_ref::<Error>(),
+            Some(Error::UnknownModelHost(_) | Error::HttpSettings | Error::Tokenizer(_))
+        )
+    })
+}
 
-    Ok(())
+/// A description of the model and the host that serves it, such as "mistral on ollama".
+fn model_description(model_options: &ModelOptions) -> String {
+    format!(
+        "{} on {}",
+        model_options.full_model_spec().model_name(),
+        model_options.host_name()
+    )
 }
 
 fn run(base_dir: PathBuf, cmdline: Vec<OsString>) -> Result<(), Report<Error>> {
-    let args = parse_main_args(cmdline).map_err(Error::CmdlineParseFailure)?;
+    let args = parse_main_args(cmdline.clone()).map_err(Error::CmdlineParseFailure)?;
 
     match args {
         FoundCommand::Run { template, args } => {
             let stdout = std::io::stdout();
             run_template(base_dir, template, args, stdout)?;
         }
-        FoundCommand::Other(_cli) => {
-            todo!()
-        }
+        FoundCommand::Other(cli) => match cli.command {
+            MainCommand::Run(run_args) => {
+                // Global arguments came before the template name, so the template arguments
+                // are parsed from the full command line.
+                let stdout = std::io::stdout();
+                run_template(base_dir, run_args.template, cmdline, stdout)?;
+            }
+            MainCommand::Test(test_args) => {
+                testing::run_tests(base_dir, test_args)?;
+            }
+            MainCommand::Snapshot(snapshot_args) => {
+                snapshot::run_snapshots(base_dir, snapshot_args)?;
+            }
+            MainCommand::Check => {
+                check::run_check(base_dir)?;
+            }
+            MainCommand::Show(show_args) => {
+                show::show_template(
+                    base_dir,
+                    &show_args.template,
+                    show_args.profile.profile.as_deref(),
+                    &mut std::io::stdout(),
+                )?;
+            }
+            MainCommand::Models(models_args) => {
+                list_models::run_list_models(base_dir, models_args, &mut std::io::stdout())?;
+            }
+            MainCommand::Doctor(doctor_args) => {
+                doctor::run_doctor(
+                    base_dir,
+                    doctor_args.profile.profile.as_deref(),
+                    &mut std::io::stdout(),
+                )?;
+            }
+            MainCommand::Config(config_args) => match config_args.command {
+                ConfigCommand::Show(show_args) => {
+                    config_command::show_config(
+                        base_dir,
+                        show_args.template.as_deref(),
+                        show_args.profile.profile.as_deref(),
+                        &mut std::io::stdout(),
+                    )?;
+                }
+                ConfigCommand::Paths => {
+                    config_command::show_config_paths(base_dir, &mut std::io::stdout())?;
+                }
+            },
+        },
     }
 
     Ok(())
@@ -160,3 +405,149 @@ fn main() -> Result<(), Report<Error>> {
         std::env::args().into_iter().map(OsString::from).collect(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::OsString,
+        sync::{Arc, Mutex},
+    };
+
+    use error_stack::Report;
+
+    use crate::{error::Error, model::ModelError, run_template, tests::base_dir};
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_with_mock_host() {
+        let cmdline = ["promptbox", "run", "mock", "--topic", "the news"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let output = SharedOutput::default();
+        run_template(base_dir(""), "mock".to_string(), cmdline, output.clone())
+            .expect("running template");
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "A short summary.\n");
+    }
+
+    #[test]
+    fn mock_echo() {
+        let cmdline = [
+            "promptbox",
+            "run",
+            "simple",
+            "--model",
+            "test",
+            "--model-host",
+            "mock",
+        ]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+        let output = SharedOutput::default();
+        run_template(base_dir(""), "simple".to_string(), cmdline, output.clone())
+            .expect("running template");
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "a simple prompt\n");
+    }
+
+    #[test]
+    fn run_with_profile() {
+        let cmdline = ["promptbox", "run", "simple", "--profile", "echo"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let output = SharedOutput::default();
+        run_template(base_dir(""), "simple".to_string(), cmdline, output.clone())
+            .expect("running template");
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "a simple prompt\n");
+    }
+
+    fn run_fallback_template(template: &str) -> (Result<(), Report<Error>>, String) {
+        let cmdline = ["promptbox", "run", template]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        let output = SharedOutput::default();
+        let result = run_template(base_dir(""), template.to_string(), cmdline, output.clone());
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn fallback_on_server_error() {
+        let (result, output) = run_fallback_template("fallback");
+        result.expect("running template");
+        assert_eq!(output, "Falling back\n");
+    }
+
+    #[test]
+    fn fallback_on_context_overflow() {
+        let (result, output) = run_fallback_template("fallback_context");
+        result.expect("running template");
+        assert_eq!(output, "Falling back\n");
+    }
+
+    #[test]
+    fn no_fallback_on_context_config_error() {
+        let (result, output) = run_fallback_template("fallback_context_config");
+        let err = result.expect_err("running template");
+        assert!(matches!(err.current_context(), Error::PreparePrompt));
+        assert!(err
+            .frames()
+            .any(|frame| matches!(frame.downcast_ref::<Error>(), Some(Error::ContextLimit))));
+        assert_eq!(output, "\n");
+    }
+
+    #[test]
+    fn no_fallback_on_client_error() {
+        let (result, output) = run_fallback_template("fallback_client_error");
+        let err = result.expect_err("running template");
+        assert!(matches!(err.current_context(), Error::RunPrompt));
+        assert!(matches!(
+            err.downcast_ref::<ModelError>(),
+            Some(ModelError::Model(401, _))
+        ));
+        assert_eq!(output, "\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_with_command_host() {
+        let cmdline = [
+            "promptbox",
+            "run",
+            "simple",
+            "--model",
+            "test",
+            "--model-host",
+            "command_host",
+        ]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+        let output = SharedOutput::default();
+        run_template(base_dir(""), "simple".to_string(), cmdline, output.clone())
+            .expect("running template");
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "from a command\n");
+    }
+}
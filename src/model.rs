@@ -6,16 +6,22 @@ use thiserror::Error;
 
 use crate::{
     args::GlobalRunArgs,
-    context::{ContextOptions, ContextOptionsInput},
+    context::{ContextOptions, ContextOptionsInput, DEFAULT_TOKENIZER},
     error::Error,
-    hosts::{HostDefinition, ModelHost},
-    option::{overwrite_from_option, overwrite_option_from_option, update_if_none},
+    hosts::{HostDefinition, ModelHost, ModelInput},
+    model_info::{ModelInfo, ModelRegistry},
+    option::{overwrite_from_option, overwrite_option_from_option},
+    provenance::{join_key, MergedKeys},
+    route::{builtin_routes, Route},
 };
 
 #[derive(Debug, Clone)]
 pub struct ModelOptions {
     pub model: ModelSpec,
-    pub openai_key: Option<String>,
+    /// Models to try in order if `model` fails
+    pub fallbacks: Vec<ModelSpec>,
+    /// An API key from the command line, which replaces the configured key for the model's host
+    pub api_key: Option<String>,
     pub temperature: f32,
     pub format: Option<OutputFormat>,
     pub top_k: Option<u32>,
@@ -31,6 +37,10 @@ pub struct ModelOptions {
     pub host: HashMap<String, HostDefinition>,
     /// The default host to use for non-OpenAI models, when no other host is specified.
     pub default_host: String,
+    /// Rules that choose the host from the model name, checked in order before `default_host`
+    pub routes: Vec<Route>,
+    /// Metadata about models, such as context length and capabilities
+    pub models: ModelRegistry,
 
     pub context: ContextOptions,
 }
@@ -42,7 +52,8 @@ impl Default for ModelOptions {
     fn default() -> Self {
         Self {
             model: ModelSpec::default(),
-            openai_key: None,
+            fallbacks: Vec::new(),
+            api_key: None,
             temperature: DEFAULT_TEMPERATURE,
             format: None,
             top_k: None,
@@ -55,6 +66,8 @@ impl Default for ModelOptions {
             alias: HashMap::new(),
             host: HostDefinition::builtin(),
             default_host: HostDefinition::default_host().to_string().to_string(),
+            routes: builtin_routes(),
+            models: ModelRegistry::default(),
         }
     }
 }
@@ -64,11 +77,15 @@ impl ModelOptions {
         value: ModelOptionsInput,
         host: HashMap<String, HostDefinition>,
         default_host: String,
+        routes: Vec<Route>,
+        models: ModelRegistry,
     ) -> Self {
+        let (model, fallbacks) = value.model.unwrap_or_default().split();
         Self {
-            model: value.model.unwrap_or_default(),
-            // For security, don't allow setting openAI key in normal config or template files.
-            openai_key: None,
+            model,
+            fallbacks,
+            // For security, don't allow setting an API key in normal config or template files.
+            api_key: None,
             temperature: value.temperature.unwrap_or(DEFAULT_TEMPERATURE),
             format: value.format,
             top_p: value.top_p,
@@ -81,6 +98,8 @@ impl ModelOptions {
             context: value.context.into(),
             host,
             default_host,
+            routes,
+            models,
         }
     }
 
@@ -93,7 +112,11 @@ impl ModelOptions {
             (_, _) => None,
         };
 
-        overwrite_from_option(&mut self.model, &model_spec);
+        if let Some(model_spec) = model_spec {
+            // A model chosen on the command line replaces any fallbacks from the template.
+            self.model = model_spec;
+            self.fallbacks.clear();
+        }
         overwrite_from_option(&mut self.temperature, &args.temperature);
         overwrite_option_from_option(&mut self.format, &args.format);
         overwrite_from_option(&mut self.context.keep, &args.overflow_keep);
@@ -104,7 +127,20 @@ impl ModelOptions {
         );
 
         // Always overwrite this since there's no other way to set the key.
-        self.openai_key = args.openai_key.clone();
+        self.api_key = args.api_key.clone();
+    }
+
+    /// Apply `--host-endpoint` overrides to the hosts.
+    pub fn update_host_endpoints(&mut self, endpoints: &[(String, String)]) -> Result<(), Error> {
+        for (name, endpoint) in endpoints {
+            let host = self
+                .host
+                .get_mut(name)
+                .ok_or_else(|| Error::UnknownModelHost(name.clone()))?;
+            host.endpoint = endpoint.clone();
+        }
+
+        Ok(())
     }
 
     pub fn full_model_spec(&self) -> ModelSpec {
@@ -114,57 +150,123 @@ impl ModelOptions {
             .unwrap_or_else(|| self.model.clone())
     }
 
-    pub fn api_host(&self) -> Result<Box<dyn ModelHost>, Error> {
+    /// The name of the host that will serve the model.
+    pub fn host_name(&self) -> String {
+        self.host_choice().0
+    }
+
+    /// The name of the host that will serve the model, and the reason it was chosen.
+    pub fn host_choice(&self) -> (String, HostChoice) {
         let model_spec = self.full_model_spec();
-        let host_name = match model_spec.host_name() {
-            Some(host) => host,
-            None => {
-                let model = model_spec.model_name();
-                if model.starts_with("gpt-4") || model.starts_with("gpt-3.5-") {
-                    "openai"
-                } else if model == "lm-studio" {
-                    "lm-studio"
-                } else {
-                    &self.default_host
-                }
-            }
-        };
+        if let Some(host) = model_spec.host_name() {
+            return (host.to_string(), HostChoice::Model);
+        }
+
+        let model = model_spec.model_name();
+        match self.routes.iter().find(|route| route.matches(model)) {
+            Some(route) => (route.host.clone(), HostChoice::Route(route.clone())),
+            None => (self.default_host.clone(), HostChoice::Default),
+        }
+    }
 
-        self.host
-            .get(host_name)
-            .ok_or(Error::UnknownModelHost(host_name.to_string()))
-            .map(|host| host.into_model_host())
+    pub fn api_host(&self) -> Result<Box<dyn ModelHost>, Report<Error>> {
+        let host_name = self.host_name();
+        let host = self
+            .host
+            .get(&host_name)
+            .ok_or_else(|| Error::UnknownModelHost(host_name.clone()))?;
+        host.into_model_host(&host_name, self.api_key.as_deref())
     }
 
-    pub fn update_from_model_input(&mut self, other: &ModelOptionsInput) {
-        overwrite_from_option(&mut self.model, &other.model);
-        overwrite_from_option(&mut self.temperature, &other.temperature);
-        overwrite_option_from_option(&mut self.format, &other.format);
-        overwrite_option_from_option(&mut self.top_p, &other.top_p);
-        overwrite_option_from_option(&mut self.top_k, &other.top_k);
-        overwrite_option_from_option(&mut self.frequency_penalty, &other.frequency_penalty);
-        overwrite_option_from_option(&mut self.presence_penalty, &other.presence_penalty);
-        overwrite_from_option(&mut self.stop, &other.stop);
-        overwrite_option_from_option(&mut self.max_tokens, &other.max_tokens);
+    /// The options for each model to try, in order: the primary model followed by its fallbacks.
+    pub fn model_chain(&self) -> Vec<ModelOptions> {
+        let primary_host = self.host_name();
+        std::iter::once(&self.model)
+            .chain(self.fallbacks.iter())
+            .map(|model| {
+                let mut options = ModelOptions {
+                    model: model.clone(),
+                    fallbacks: Vec::new(),
+                    ..self.clone()
+                };
+                // A key from the command line is only for the primary model's host, and shouldn't
+                // be sent to other hosts.
+                if options.host_name() != primary_host {
+                    options.api_key = None;
+                }
+                options
+            })
+            .collect()
+    }
+
+    pub fn update_from_model_input(&mut self, other: &ModelOptionsInput) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        if let Some(chain) = other.model.as_ref() {
+            (self.model, self.fallbacks) = chain.clone().split();
+            merged.add("model");
+        }
+        merged.overwrite_from_option("temperature", &mut self.temperature, &other.temperature);
+        merged.overwrite_option_from_option("format", &mut self.format, &other.format);
+        merged.overwrite_option_from_option("top_p", &mut self.top_p, &other.top_p);
+        merged.overwrite_option_from_option("top_k", &mut self.top_k, &other.top_k);
+        merged.overwrite_option_from_option(
+            "frequency_penalty",
+            &mut self.frequency_penalty,
+            &other.frequency_penalty,
+        );
+        merged.overwrite_option_from_option(
+            "presence_penalty",
+            &mut self.presence_penalty,
+            &other.presence_penalty,
+        );
+        merged.overwrite_from_option("stop", &mut self.stop, &other.stop);
+        merged.overwrite_option_from_option("max_tokens", &mut self.max_tokens, &other.max_tokens);
 
         for (key, value) in &other.alias {
             if !self.alias.contains_key(key) {
                 self.alias.insert(key.clone(), value.clone());
+                merged.add(&join_key("alias", key));
             }
         }
+
+        merged
+    }
+
+    /// Metadata about the model from the model registry, if it has any.
+    pub fn model_info(&self) -> Option<&ModelInfo> {
+        self.models.get(self.full_model_spec().model_name())
+    }
+
+    /// The tokenizer to count tokens with for the model, from the model registry or the default.
+    pub fn tokenizer_name(&self) -> &str {
+        self.model_info()
+            .and_then(|info| info.tokenizer.as_deref())
+            .unwrap_or(DEFAULT_TOKENIZER)
     }
 
     /// Get the input context size limit for a model.
     /// The returned value is the total context size minus `self.context.reserve_output`.
-    /// This may do a network request for Ollama models.
+    /// The model registry is checked first, and then the host, which may do a network request
+    /// for Ollama models. Hosts that set `limit_context_length = false` are not asked.
     pub fn context_limit(&self) -> Result<Option<usize>, Report<Error>> {
-        let model = self.full_model_spec();
-        let model_name = model.model_name();
-
-        let comms = self.api_host()?;
-        let limit = comms
-            .model_context_limit(model_name)
-            .change_context(Error::ContextLimit)?;
+        let limit = match self.model_info().and_then(|info| info.context_length) {
+            Some(limit) => Some(limit),
+            None => {
+                let host_name = self.host_name();
+                let host = self
+                    .host
+                    .get(&host_name)
+                    .ok_or_else(|| Error::UnknownModelHost(host_name.clone()))?;
+                if host.limit_context_length {
+                    let model = self.full_model_spec();
+                    self.api_host()?
+                        .model_context_limit(model.model_name())
+                        .change_context(Error::ContextLimit)?
+                } else {
+                    None
+                }
+            }
+        };
 
         let Some(limit) = limit else {
             return Ok(None);
@@ -180,6 +282,60 @@ impl ModelOptions {
 
         Ok(Some(limit - self.context.reserve_output))
     }
+
+    /// Check that the model supports what the request uses, according to the model registry.
+    /// Models without registry entries, or without a value for a capability, are assumed to
+    /// support it.
+    pub fn check_capabilities(&self, input: &ModelInput) -> Result<(), Report<ModelError>> {
+        let Some(info) = self.model_info() else {
+            return Ok(());
+        };
+
+        let model = self.full_model_spec().model_name().to_string();
+        let unsupported =
+            |feature: String| Err(Report::new(ModelError::Unsupported(model.clone(), feature)));
+
+        if !input.images.is_empty() && info.supports_images == Some(false) {
+            return unsupported("images".to_string());
+        }
+
+        if self.format == Some(OutputFormat::JSON) && info.supports_json == Some(false) {
+            return unsupported("JSON output".to_string());
+        }
+
+        if input.system.is_some() && info.supports_system == Some(false) {
+            return unsupported("system prompts".to_string());
+        }
+
+        if let (Some(max_tokens), Some(max_output)) = (self.max_tokens, info.max_output) {
+            if max_tokens > max_output {
+                return unsupported(format!("max_tokens above {max_output}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How the host for a model was chosen
+#[derive(Debug, Clone)]
+pub enum HostChoice {
+    /// The model spec names the host
+    Model,
+    /// The model name matched a route
+    Route(Route),
+    /// No route matched, so the default host is used
+    Default,
+}
+
+impl std::fmt::Display for HostChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Model => write!(f, "set by the model"),
+            Self::Route(route) => write!(f, "matched {route}"),
+            Self::Default => write!(f, "default host"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -267,12 +423,73 @@ impl From<String> for ModelSpec {
     }
 }
 
+impl std::fmt::Display for ModelSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host_name() {
+            Some(host) => write!(f, "{} on {host}", self.model_name()),
+            None => write!(f, "{}", self.model_name()),
+        }
+    }
+}
+
+/// A model, followed by the models to fall back to if it fails. In a configuration file, this
+/// can be a single model or a list of models.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelChain(Vec<ModelSpec>);
+
+impl ModelChain {
+    /// Split the chain into the primary model and its fallbacks.
+    pub fn split(self) -> (ModelSpec, Vec<ModelSpec>) {
+        let mut models = self.0.into_iter();
+        let model = models.next().unwrap_or_default();
+        (model, models.collect())
+    }
+}
+
+impl Default for ModelChain {
+    fn default() -> Self {
+        Self(vec![ModelSpec::default()])
+    }
+}
+
+impl From<ModelSpec> for ModelChain {
+    fn from(value: ModelSpec) -> Self {
+        Self(vec![value])
+    }
+}
+
+impl From<String> for ModelChain {
+    fn from(value: String) -> Self {
+        Self(vec![ModelSpec::Plain(value)])
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelChain {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ChainInput {
+            Single(ModelSpec),
+            List(Vec<ModelSpec>),
+        }
+
+        match ChainInput::deserialize(deserializer)? {
+            ChainInput::Single(model) => Ok(Self(vec![model])),
+            ChainInput::List(models) if models.is_empty() => {
+                Err(serde::de::Error::custom("model list must not be empty"))
+            }
+            ChainInput::List(models) => Ok(Self(models)),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct ModelOptionsInput {
-    pub model: Option<ModelSpec>,
-    pub lm_studio_host: Option<String>,
-    pub ollama_host: Option<String>,
+    pub model: Option<ModelChain>,
     pub temperature: Option<f32>,
     pub format: Option<OutputFormat>,
     pub top_p: Option<f32>,
@@ -290,27 +507,38 @@ pub struct ModelOptionsInput {
 }
 
 impl ModelOptionsInput {
-    /// For any members that are `None` in this `ModelOptions`, use the value from `other`
-    pub fn merge_defaults(&mut self, other: &ModelOptionsInput) {
-        update_if_none(&mut self.model, &other.model);
-        update_if_none(&mut self.lm_studio_host, &other.lm_studio_host);
-        update_if_none(&mut self.ollama_host, &other.ollama_host);
-        update_if_none(&mut self.temperature, &other.temperature);
-        update_if_none(&mut self.format, &other.format);
-        update_if_none(&mut self.top_p, &other.top_p);
-        update_if_none(&mut self.top_k, &other.top_k);
-        update_if_none(&mut self.frequency_penalty, &other.frequency_penalty);
-        update_if_none(&mut self.presence_penalty, &other.presence_penalty);
-        update_if_none(&mut self.stop, &other.stop);
-        update_if_none(&mut self.max_tokens, &other.max_tokens);
-
-        self.context.merge_defaults(&other.context);
+    /// For any members that are `None` in this `ModelOptions`, use the value from `other`.
+    /// Returns the keys of the values that were taken from `other`.
+    pub fn merge_defaults(&mut self, other: &ModelOptionsInput) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        merged.update_if_none("model", &mut self.model, &other.model);
+        merged.update_if_none("temperature", &mut self.temperature, &other.temperature);
+        merged.update_if_none("format", &mut self.format, &other.format);
+        merged.update_if_none("top_p", &mut self.top_p, &other.top_p);
+        merged.update_if_none("top_k", &mut self.top_k, &other.top_k);
+        merged.update_if_none(
+            "frequency_penalty",
+            &mut self.frequency_penalty,
+            &other.frequency_penalty,
+        );
+        merged.update_if_none(
+            "presence_penalty",
+            &mut self.presence_penalty,
+            &other.presence_penalty,
+        );
+        merged.update_if_none("stop", &mut self.stop, &other.stop);
+        merged.update_if_none("max_tokens", &mut self.max_tokens, &other.max_tokens);
+
+        merged.extend_under("context", self.context.merge_defaults(&other.context));
 
         for (key, value) in &other.alias {
             if !self.alias.contains_key(key) {
                 self.alias.insert(key.clone(), value.clone());
+                merged.add(&join_key("alias", key));
             }
         }
+
+        merged
     }
 }
 
@@ -328,6 +556,47 @@ pub enum ModelError {
     FormatPrompt,
     #[error("Host does not support images")]
     HostDoesNotSupportImages,
+    #[error("Failed to read mock fixtures")]
+    MockFixtures,
+    #[error("Error running model command")]
+    Command,
+    #[error("Failed to get the API key for host {0}")]
+    ApiKey(String),
+    #[error("Model {0} does not support {1}")]
+    Unsupported(String, String),
+    #[error("Host does not support listing models")]
+    ListModelsUnsupported,
+}
+
+impl ModelError {
+    /// Whether trying a different model might succeed where this error failed: the host could not
+    /// be reached, had a server error, was still rate limiting after retries, the prompt did
+    /// not fit in the model's context, or the model doesn't support something in the request.
+    pub fn should_fall_back(&self) -> bool {
+        match self {
+            ModelError::Raw | ModelError::Command | ModelError::Unsupported(..) => true,
+            ModelError::Model(code, message) => {
+                *code == 429 || *code >= 500 || is_context_overflow(*code, message)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_context_overflow(code: u16, message: &str) -> bool {
+    if code != 400 && code != 413 {
+        return false;
+    }
+
+    let message = message.to_lowercase();
+    [
+        "context length",
+        "context_length",
+        "context window",
+        "too many tokens",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
 }
 
 pub fn map_model_response_err(err: ureq::Error) -> Report<ModelError> {
@@ -344,6 +613,96 @@ pub fn map_model_response_err(err: ureq::Error) -> Report<ModelError> {
 mod test {
     use super::*;
 
+    mod fallback {
+        use super::*;
+
+        #[derive(Deserialize, Debug)]
+        struct Input {
+            model: ModelChain,
+        }
+
+        #[test]
+        fn parse_chain() {
+            let input: Input = toml::from_str(
+                r#"model = ["gpt-4-turbo", { model = "mixtral", host = "together" }, "mistral"]"#,
+            )
+            .unwrap();
+            let (model, fallbacks) = input.model.split();
+            assert_eq!(model, ModelSpec::Plain("gpt-4-turbo".to_string()));
+            assert_eq!(
+                fallbacks,
+                vec![
+                    ModelSpec::Full {
+                        model: "mixtral".to_string(),
+                        host: Some("together".to_string())
+                    },
+                    ModelSpec::Plain("mistral".to_string())
+                ]
+            );
+        }
+
+        #[test]
+        fn parse_single() {
+            let input: Input = toml::from_str(r#"model = "mistral""#).unwrap();
+            assert_eq!(input.model, ModelChain::from("mistral".to_string()));
+        }
+
+        #[test]
+        fn empty_chain() {
+            toml::from_str::<Input>("model = []").expect_err("parsing empty list");
+        }
+
+        #[test]
+        fn model_chain() {
+            let mut options = ModelOptions::default();
+            options.update_from_model_input(&ModelOptionsInput {
+                model: Some(ModelChain(vec![
+                    "gpt-4".to_string().into(),
+                    ModelSpec::Full {
+                        model: "mixtral".to_string(),
+                        host: Some("together".to_string()),
+                    },
+                ])),
+                ..Default::default()
+            });
+
+            let chain = options.model_chain();
+            assert_eq!(chain.len(), 2);
+            assert_eq!(chain[0].host_name(), "openai");
+            assert_eq!(chain[1].host_name(), "together");
+            assert!(chain.iter().all(|options| options.fallbacks.is_empty()));
+        }
+
+        #[test]
+        fn command_line_model_replaces_chain() {
+            let mut options = ModelOptions {
+                fallbacks: vec!["mistral".to_string().into()],
+                ..Default::default()
+            };
+            options.update_from_args(&GlobalRunArgs {
+                model: Some("llama2".to_string()),
+                ..Default::default()
+            });
+            assert_eq!(options.model, ModelSpec::Plain("llama2".to_string()));
+            assert!(options.fallbacks.is_empty());
+        }
+
+        #[test]
+        fn errors_that_fall_back() {
+            assert!(ModelError::Raw.should_fall_back());
+            assert!(ModelError::Model(503, String::new()).should_fall_back());
+            assert!(ModelError::Model(429, String::new()).should_fall_back());
+            assert!(ModelError::Model(
+                400,
+                "This model's maximum context length is 4097 tokens".to_string()
+            )
+            .should_fall_back());
+            assert!(!ModelError::Model(400, "Invalid temperature".to_string()).should_fall_back());
+            assert!(!ModelError::Model(401, String::new()).should_fall_back());
+            assert!(!ModelError::Deserialize.should_fall_back());
+        }
+    }
+
     mod host {
         use super::*;
 
@@ -356,7 +715,7 @@ mod test {
             };
 
             let err = options.api_host().unwrap_err();
-            assert!(matches!(err, Error::UnknownModelHost(_)));
+            assert!(matches!(err.current_context(), Error::UnknownModelHost(_)));
         }
 
         #[test]
@@ -370,7 +729,7 @@ mod test {
             };
 
             let err = options.api_host().unwrap_err();
-            assert!(matches!(err, Error::UnknownModelHost(_)));
+            assert!(matches!(err.current_context(), Error::UnknownModelHost(_)));
         }
 
         #[test]
@@ -386,6 +745,23 @@ mod test {
             let host_desc = format!("{host:?}");
             assert!(host_desc.contains("OpenAiHost"));
         }
+
+        #[test]
+        fn host_endpoint_override() {
+            let mut options = ModelOptions::default();
+            options
+                .update_host_endpoints(&[(
+                    "lm-studio".to_string(),
+                    "http://gpu-box:1234".to_string(),
+                )])
+                .unwrap();
+            assert_eq!(options.host["lm-studio"].endpoint, "http://gpu-box:1234");
+
+            let err = options
+                .update_host_endpoints(&[("nope".to_string(), "http://x".to_string())])
+                .unwrap_err();
+            assert!(matches!(err, Error::UnknownModelHost(name) if name == "nope"));
+        }
     }
 
     mod context_length {
@@ -421,6 +797,102 @@ mod test {
             let err = options.context_limit().unwrap_err();
             assert!(matches!(err.current_context(), Error::ContextLimit));
         }
+
+        #[test]
+        fn registry_used_for_unlimited_hosts() {
+            let options = ModelOptions {
+                model: ModelSpec::Full {
+                    model: "gpt-4".to_string(),
+                    host: Some("openrouter".to_string()),
+                },
+                ..Default::default()
+            };
+            assert_eq!(options.context_limit().unwrap(), Some(8192 - 256));
+        }
+
+        #[test]
+        fn unlimited_host_not_asked() {
+            let mut options = ModelOptions {
+                model: ModelSpec::Full {
+                    model: "unknown-model".to_string(),
+                    host: Some("small".to_string()),
+                },
+                ..Default::default()
+            };
+            let host = HostDefinition {
+                protocol: crate::hosts::HostProtocol::Mock,
+                limit_context_length: true,
+                mock: crate::hosts::mock::MockOptions {
+                    context_limit: Some(1000),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            options.host.insert("small".to_string(), host);
+            assert_eq!(options.context_limit().unwrap(), Some(1000 - 256));
+
+            options.host.get_mut("small").unwrap().limit_context_length = false;
+            assert_eq!(options.context_limit().unwrap(), None);
+        }
+    }
+
+    mod capabilities {
+        use super::*;
+        use crate::image::ImageData;
+
+        fn check(model: &str, input: ModelInput, max_tokens: Option<u32>) -> Option<String> {
+            let options = ModelOptions {
+                model: model.to_string().into(),
+                max_tokens,
+                ..Default::default()
+            };
+            options
+                .check_capabilities(&input)
+                .err()
+                .map(|e| e.current_context().to_string())
+        }
+
+        fn input(system: Option<&'static str>, images: Vec<ImageData>) -> ModelInput<'static> {
+            ModelInput {
+                prompt: "hello",
+                system,
+                images,
+            }
+        }
+
+        #[test]
+        fn supported() {
+            assert_eq!(
+                check("gpt-4o", input(Some("Be brief"), vec![]), Some(1000)),
+                None
+            );
+            // Models that aren't in the registry aren't checked.
+            assert_eq!(
+                check("llama2", input(Some("Be brief"), vec![]), Some(1000000)),
+                None
+            );
+        }
+
+        #[test]
+        fn unsupported() {
+            assert_eq!(
+                check("o1-mini", input(Some("Be brief"), vec![]), None).as_deref(),
+                Some("Model o1-mini does not support system prompts")
+            );
+            assert_eq!(
+                check("gpt-4", input(None, vec![]), Some(10000)).as_deref(),
+                Some("Model gpt-4 does not support max_tokens above 8192")
+            );
+
+            let image = ImageData {
+                mimetype: "image/png".to_string(),
+                contents: vec![],
+            };
+            assert_eq!(
+                check("gpt-3.5-turbo", input(None, vec![image]), None).as_deref(),
+                Some("Model gpt-3.5-turbo does not support images")
+            );
+        }
     }
 
     mod model_spec {
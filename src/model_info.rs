@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::provenance::MergedKeys;
+
+/// Metadata about a model, from a `[models."name"]` section in the configuration or the built-in
+/// table.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelInfo {
+    /// The total context length, in tokens
+    pub context_length: Option<usize>,
+    /// The most tokens the model can generate in a response
+    pub max_output: Option<u32>,
+    pub supports_images: Option<bool>,
+    pub supports_json: Option<bool>,
+    pub supports_system: Option<bool>,
+    /// A tokenizer on the Hugging Face Hub to use when counting tokens for this model
+    pub tokenizer: Option<String>,
+    pub price: Option<ModelPrice>,
+}
+
+impl ModelInfo {
+    /// For any members that are `None` in this `ModelInfo`, use the value from `other`.
+    /// Returns the keys of the values that were taken from `other`.
+    pub fn merge_defaults(&mut self, other: &ModelInfo) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        merged.update_if_none(
+            "context_length",
+            &mut self.context_length,
+            &other.context_length,
+        );
+        merged.update_if_none("max_output", &mut self.max_output, &other.max_output);
+        merged.update_if_none(
+            "supports_images",
+            &mut self.supports_images,
+            &other.supports_images,
+        );
+        merged.update_if_none(
+            "supports_json",
+            &mut self.supports_json,
+            &other.supports_json,
+        );
+        merged.update_if_none(
+            "supports_system",
+            &mut self.supports_system,
+            &other.supports_system,
+        );
+        merged.update_if_none("tokenizer", &mut self.tokenizer, &other.tokenizer);
+        merged.update_if_none("price", &mut self.price, &other.price);
+        merged
+    }
+}
+
+/// The price of a model, in US dollars per million tokens
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Model metadata from the configuration, layered over the built-in table.
+#[derive(Debug, Clone)]
+pub struct ModelRegistry(HashMap<String, ModelInfo>);
+
+impl ModelRegistry {
+    pub fn new(configured: HashMap<String, ModelInfo>) -> Self {
+        let mut models = builtin_models();
+        for (name, info) in configured {
+            match models.get_mut(&name) {
+                Some(builtin) => {
+                    let mut info = info;
+                    info.merge_defaults(builtin);
+                    *builtin = info;
+                }
+                None => {
+                    models.insert(name, info);
+                }
+            }
+        }
+
+        Self(models)
+    }
+
+    /// Find the metadata for a model. If there is no entry with the exact name, this uses the
+    /// longest entry that is a prefix of the name followed by a `-` or `:`, so that
+    /// `gpt-4-0613` uses the entry for `gpt-4` and `llama3:8b` uses the entry for `llama3`.
+    pub fn get(&self, model: &str) -> Option<&ModelInfo> {
+        if let Some(info) = self.0.get(model) {
+            return Some(info);
+        }
+
+        self.0
+            .iter()
+            .filter(|(name, _)| {
+                model
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.starts_with('-') || rest.starts_with(':'))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, info)| info)
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new(HashMap::new())
+    }
+}
+
+/// Metadata for well-known models whose hosts don't provide it through their APIs: name, context
+/// length, max output, supports images, JSON, and system prompts, and input and output price.
+type BuiltinModel = (&'static str, usize, u32, bool, bool, bool, f64, f64);
+
+#[rustfmt::skip]
+const BUILTIN_MODELS: &[BuiltinModel] = &[
+    ("gpt-3.5-turbo",          16385,  4096,   false, true,  true,  0.5,  1.5),
+    ("gpt-3.5-turbo-0613",     4096,   4096,   false, false, true,  1.5,  2.0),
+    ("gpt-3.5-turbo-16k",      16385,  4096,   false, false, true,  3.0,  4.0),
+    ("gpt-3.5-turbo-instruct", 4096,   4096,   false, false, true,  1.5,  2.0),
+    ("gpt-4",                  8192,   8192,   false, false, true,  30.0, 60.0),
+    ("gpt-4-32k",              32768,  8192,   false, false, true,  60.0, 120.0),
+    ("gpt-4-1106-preview",     128000, 4096,   false, true,  true,  10.0, 30.0),
+    ("gpt-4-0125-preview",     128000, 4096,   false, true,  true,  10.0, 30.0),
+    ("gpt-4-turbo-preview",    128000, 4096,   false, true,  true,  10.0, 30.0),
+    ("gpt-4-vision-preview",   128000, 4096,   true,  false, true,  10.0, 30.0),
+    ("gpt-4-turbo",            128000, 4096,   true,  true,  true,  10.0, 30.0),
+    ("gpt-4o",                 128000, 16384,  true,  true,  true,  2.5,  10.0),
+    ("gpt-4o-mini",            128000, 16384,  true,  true,  true,  0.15, 0.6),
+    ("o1",                     200000, 100000, true,  true,  true,  15.0, 60.0),
+    ("o1-preview",             128000, 32768,  false, false, false, 15.0, 60.0),
+    ("o1-mini",                128000, 65536,  false, false, false, 3.0,  12.0),
+];
+
+fn builtin_models() -> HashMap<String, ModelInfo> {
+    BUILTIN_MODELS
+        .iter()
+        .map(
+            |&(name, context_length, max_output, images, json, system, input, output)| {
+                let info = ModelInfo {
+                    context_length: Some(context_length),
+                    max_output: Some(max_output),
+                    supports_images: Some(images),
+                    supports_json: Some(json),
+                    supports_system: Some(system),
+                    tokenizer: None,
+                    price: Some(ModelPrice { input, output }),
+                };
+                (name.to_string(), info)
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{ModelInfo, ModelRegistry};
+
+    /// Check against a bunch of real models to make sure the lookup is right
+    #[test]
+    fn builtin_context_lengths() {
+        let registry = ModelRegistry::default();
+        let context_length = |model: &str| registry.get(model).and_then(|i| i.context_length);
+
+        assert_eq!(context_length("gpt-3.5-turbo"), Some(16385));
+        assert_eq!(context_length("gpt-3.5-turbo-16k"), Some(16385));
+        assert_eq!(context_length("gpt-3.5-turbo-1106"), Some(16385));
+        assert_eq!(context_length("gpt-3.5-turbo-instruct"), Some(4096));
+        assert_eq!(context_length("gpt-3.5-turbo-0613"), Some(4096));
+        assert_eq!(context_length("gpt-4-1106-preview"), Some(128000));
+        assert_eq!(context_length("gpt-4-vision-preview"), Some(128000));
+        assert_eq!(context_length("gpt-4"), Some(8192));
+        assert_eq!(context_length("gpt-4-0613"), Some(8192));
+        assert_eq!(context_length("gpt-4-32k"), Some(32768));
+        assert_eq!(context_length("gpt-4-32k-0613"), Some(32768));
+        assert_eq!(context_length("gpt-4-turbo-2024-04-09"), Some(128000));
+        assert_eq!(context_length("gpt-4o"), Some(128000));
+        assert_eq!(context_length("gpt-4o-2024-08-06"), Some(128000));
+        assert_eq!(context_length("gpt-4o-mini"), Some(128000));
+        assert_eq!(context_length("o1"), Some(200000));
+        assert_eq!(context_length("o1-mini-2024-09-12"), Some(128000));
+        assert_eq!(context_length("gpt-4x"), None);
+        assert_eq!(context_length("mistral"), None);
+    }
+
+    #[test]
+    fn configured_models() {
+        let configured: HashMap<String, ModelInfo> = toml::from_str(
+            r#"
+            [gpt-4o]
+            max_output = 4096
+            price = { input = 5.0, output = 15.0 }
+
+            [llama3]
+            context_length = 8192
+            supports_images = false
+            tokenizer = "meta-llama/Meta-Llama-3-8B"
+            "#,
+        )
+        .unwrap();
+        let registry = ModelRegistry::new(configured);
+
+        let gpt4o = registry.get("gpt-4o").unwrap();
+        assert_eq!(gpt4o.max_output, Some(4096));
+        assert_eq!(gpt4o.price.unwrap().input, 5.0);
+        // Fields that aren't configured come from the built-in table.
+        assert_eq!(gpt4o.context_length, Some(128000));
+        assert_eq!(gpt4o.supports_images, Some(true));
+
+        let llama = registry.get("llama3:8b-instruct").unwrap();
+        assert_eq!(llama.context_length, Some(8192));
+        assert_eq!(llama.supports_json, None);
+        assert_eq!(
+            llama.tokenizer.as_deref(),
+            Some("meta-llama/Meta-Llama-3-8B")
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::option::{overwrite_from_option, overwrite_option_from_option, update_if_none};
+
+/// Where a configuration value was set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    File(PathBuf),
+    /// A `promptbox` directory without a configuration file, which is used as a template directory
+    Directory(PathBuf),
+    Env(String),
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Directory(path) => write!(f, "directory {}", path.display()),
+            Origin::Env(var) => write!(f, "environment variable {var}"),
+        }
+    }
+}
+
+/// Configuration values flattened to dotted keys, such as `model.temperature`, along with where
+/// each one was set. Keys whose values are appended, such as `templates`, can have more than one
+/// value.
+#[derive(Debug, Clone, Default)]
+pub struct Provenance(BTreeMap<String, Vec<(toml::Value, Origin)>>);
+
+impl Provenance {
+    /// Flatten the values in a table, using `prefix` as the start of each key.
+    pub fn from_table(prefix: &str, table: &toml::Table, origin: &Origin) -> Self {
+        let mut provenance = Self::default();
+        provenance.add_table(prefix, table, origin);
+        provenance
+    }
+
+    fn add_table(&mut self, prefix: &str, table: &toml::Table, origin: &Origin) {
+        for (key, value) in table {
+            let key = join_key(prefix, key);
+            match value {
+                toml::Value::Table(table) => self.add_table(&key, table, origin),
+                value => self.set(&key, value.clone(), origin.clone()),
+            }
+        }
+    }
+
+    /// Set the value for a key, replacing any existing values.
+    pub fn set(&mut self, key: &str, value: toml::Value, origin: Origin) {
+        self.0.insert(key.to_string(), vec![(value, origin)]);
+    }
+
+    /// For each key that a merge took from `other`, replace the values for that key and any keys
+    /// under it with the values from `other`.
+    pub fn take_merged(&mut self, other: &Provenance, merged: &MergedKeys) {
+        for key in &merged.0 {
+            self.remove(key);
+            let values = other
+                .0
+                .iter()
+                .filter(|(other_key, _)| is_under(other_key, key))
+                .map(|(key, values)| (key.clone(), values.clone()))
+                .collect::<Vec<_>>();
+            self.0.extend(values);
+        }
+    }
+
+    /// Add the values for an array, such as `templates`, after the existing values.
+    pub fn append(&mut self, other: &Provenance, key: &str) {
+        let Some(values) = other.0.get(key) else {
+            return;
+        };
+
+        let existing = self.0.entry(key.to_string()).or_default();
+        for (value, origin) in values {
+            match value {
+                toml::Value::Array(items) => {
+                    existing.extend(items.iter().map(|item| (item.clone(), origin.clone())))
+                }
+                value => existing.push((value.clone(), origin.clone())),
+            }
+        }
+    }
+
+    /// Remove the values for a key and any keys under it.
+    pub fn remove(&mut self, key: &str) {
+        self.0.retain(|existing, _| !is_under(existing, key));
+    }
+
+    /// Iterate over each key and value, sorted by key, with appended values in merge order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &toml::Value, &Origin)> {
+        self.0.iter().flat_map(|(key, values)| {
+            values
+                .iter()
+                .map(move |(value, origin)| (key.as_str(), value, origin))
+        })
+    }
+}
+
+/// Add a segment to a dotted key, quoting it if it isn't a bare TOML key.
+pub fn join_key(prefix: &str, key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let key = if bare {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    };
+
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+/// Whether `key` is `prefix` or one of the keys under it.
+fn is_under(key: &str, prefix: &str) -> bool {
+    key.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// The dotted keys of the values that a merge took from the other side, so that their origins
+/// can be taken as well.
+#[derive(Debug, Default)]
+pub struct MergedKeys(Vec<String>);
+
+impl MergedKeys {
+    pub fn add(&mut self, key: &str) {
+        self.0.push(key.to_string());
+    }
+
+    pub fn extend(&mut self, other: MergedKeys) {
+        self.0.extend(other.0);
+    }
+
+    /// Add the keys from merging a nested value, under `prefix`.
+    pub fn extend_under(&mut self, prefix: &str, nested: MergedKeys) {
+        self.0
+            .extend(nested.0.into_iter().map(|key| format!("{prefix}.{key}")));
+    }
+
+    /// [update_if_none], recording `key` if the value from `b` is used.
+    pub fn update_if_none<T: Clone>(&mut self, key: &str, a: &mut Option<T>, b: &Option<T>) {
+        if a.is_none() && b.is_some() {
+            self.add(key);
+        }
+        update_if_none(a, b);
+    }
+
+    /// [overwrite_option_from_option], recording `key` if the value from `b` is used.
+    pub fn overwrite_option_from_option<T: Clone>(
+        &mut self,
+        key: &str,
+        a: &mut Option<T>,
+        b: &Option<T>,
+    ) {
+        if b.is_some() {
+            self.add(key);
+        }
+        overwrite_option_from_option(a, b);
+    }
+
+    /// [overwrite_from_option], recording `key` if the value from `b` is used.
+    pub fn overwrite_from_option<T: Clone>(&mut self, key: &str, a: &mut T, b: &Option<T>) {
+        if b.is_some() {
+            self.add(key);
+        }
+        overwrite_from_option(a, b);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(name: &str) -> Origin {
+        Origin::File(PathBuf::from(name))
+    }
+
+    fn provenance(contents: &str, origin: &str) -> Provenance {
+        let table = toml::from_str::<toml::Table>(contents).unwrap();
+        Provenance::from_table("", &table, &file(origin))
+    }
+
+    #[test]
+    fn take_merged() {
+        let near = provenance(
+            r#"
+            templates = ["a"]
+            [model]
+            temperature = 0.5
+            [host.local]
+            endpoint = "http://near"
+            "#,
+            "near.toml",
+        );
+        let far = provenance(
+            r#"
+            templates = ["b", "c"]
+            [model]
+            temperature = 0.9
+            top_p = 0.5
+            [host.local]
+            endpoint = "http://far"
+            [host.local.headers]
+            X-Team = "far"
+            "#,
+            "far.toml",
+        );
+
+        let mut merged = Provenance::default();
+        merged.append(&near, "templates");
+        let mut keys = MergedKeys::default();
+        keys.add("model.temperature");
+        keys.add("host.local");
+        merged.take_merged(&near, &keys);
+
+        merged.append(&far, "templates");
+        let mut keys = MergedKeys::default();
+        keys.add("model.top_p");
+        keys.add("host.local");
+        merged.take_merged(&far, &keys);
+
+        let values = merged
+            .iter()
+            .map(|(key, value, origin)| format!("{key} = {value} ({origin})"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![
+                r#"host.local.endpoint = "http://far" (far.toml)"#,
+                r#"host.local.headers.X-Team = "far" (far.toml)"#,
+                "model.temperature = 0.5 (near.toml)",
+                "model.top_p = 0.5 (far.toml)",
+                r#"templates = "a" (near.toml)"#,
+                r#"templates = "b" (far.toml)"#,
+                r#"templates = "c" (far.toml)"#,
+            ]
+        );
+    }
+}
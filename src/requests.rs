@@ -1,33 +1,728 @@
-use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    env::expand_env_vars,
+    error::Error,
+    provenance::{join_key, MergedKeys},
+};
+
+/// Save each request and its response to files in this directory.
+pub const RECORD_ENV: &str = "PROMPTBOX_RECORD";
+/// Answer requests from the files saved by [RECORD_ENV] instead of sending them.
+pub const REPLAY_ENV: &str = "PROMPTBOX_REPLAY";
+
+/// Replaces the values of the host's extra query parameters in recordings, since they often hold
+/// secrets.
+const REDACTED: &str = "REDACTED";
+
+/// Headers that describe how the body was sent over the wire, which no longer apply once the
+/// body has been read.
+const SKIPPED_HEADERS: &[&str] = &["content-length", "transfer-encoding", "content-encoding"];
+
+enum RecordMode {
+    Off,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+#[cfg(test)]
+thread_local! {
+    static REPLAY_OVERRIDE: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Replay recordings from `dir` for requests made by `f` on this thread, regardless of the
+/// environment.
+#[cfg(test)]
+pub fn with_replay<T>(dir: PathBuf, f: impl FnOnce() -> T) -> T {
+    REPLAY_OVERRIDE.with(|r| r.replace(Some(dir)));
+    let result = f();
+    REPLAY_OVERRIDE.with(|r| r.replace(None));
+    result
+}
+
+fn record_mode() -> RecordMode {
+    #[cfg(test)]
+    if let Some(dir) = REPLAY_OVERRIDE.with(|r| r.borrow().clone()) {
+        return RecordMode::Replay(dir);
+    }
+
+    if let Some(dir) = std::env::var_os(REPLAY_ENV).filter(|d| !d.is_empty()) {
+        RecordMode::Replay(PathBuf::from(dir))
+    } else if let Some(dir) = std::env::var_os(RECORD_ENV).filter(|d| !d.is_empty()) {
+        RecordMode::Record(PathBuf::from(dir))
+    } else {
+        RecordMode::Off
+    }
+}
+
+/// A request and its response, as saved to a recording file.
+#[derive(Serialize, Deserialize, Debug)]
+struct Recording {
+    method: String,
+    url: String,
+    /// The JSON body of the request. Requests match if the bodies are equal, ignoring formatting
+    /// and the order of object keys.
+    body: Option<serde_json::Value>,
+    response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RecordedResponse {
+    status: u16,
+    status_text: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    /// The full response body. For streamed responses, this contains every chunk.
+    body: String,
+}
+
+impl RecordedResponse {
+    fn from_response(response: ureq::Response) -> std::io::Result<Self> {
+        let status = response.status();
+        let status_text = response.status_text().to_string();
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter(|name| !SKIPPED_HEADERS.contains(&name.as_str()))
+            .flat_map(|name| {
+                response
+                    .all(&name)
+                    .into_iter()
+                    .map(|value| (name.clone(), value.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body)?;
+
+        Ok(Self {
+            status,
+            status_text,
+            headers,
+            body,
+        })
+    }
+
+    /// Turn the recording back into a response, returning an error for failure statuses just as
+    /// ureq does.
+    #[allow(clippy::result_large_err)]
+    fn to_response(&self) -> Result<ureq::Response, ureq::Error> {
+        let mut raw = format!("HTTP/1.1 {} {}\r\n", self.status, self.status_text);
+        for (name, value) in &self.headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        raw.push_str(&self.body);
+
+        let response = raw.parse::<ureq::Response>()?;
+        if self.status >= 400 {
+            Err(ureq::Error::Status(self.status, response))
+        } else {
+            Ok(response)
+        }
+    }
+}
+
+fn recording_path(
+    dir: &Path,
+    method: &str,
+    url: &str,
+    body: &Option<serde_json::Value>,
+) -> PathBuf {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    method.hash(&mut hasher);
+    url.hash(&mut hasher);
+    body.as_ref().map(|b| b.to_string()).hash(&mut hasher);
+
+    let endpoint = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    dir.join(format!(
+        "{}-{endpoint}-{:016x}.json",
+        method.to_lowercase(),
+        hasher.finish()
+    ))
+}
+
+/// Find the recorded response for a request.
+#[allow(clippy::result_large_err)]
+fn replay(
+    dir: &Path,
+    method: &str,
+    url: &str,
+    body: &Option<serde_json::Value>,
+) -> Result<ureq::Response, ureq::Error> {
+    let recordings = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|contents| serde_json::from_str::<Recording>(&contents).ok());
+
+    for recording in recordings {
+        if recording.method == method && recording.url == url && &recording.body == body {
+            return recording.response.to_response();
+        }
+    }
+
+    let message = format!(
+        "No recording in {} for {method} {url} {}",
+        dir.display(),
+        body.as_ref().map(|b| b.to_string()).unwrap_or_default()
+    );
+    let response = ureq::Response::new(404, "No Recording", &message)?;
+    Err(ureq::Error::Status(404, response))
+}
+
+/// The request's URL, including any query parameters added to the request. The values of the
+/// `redacted` query parameters are replaced so that they aren't saved in recordings.
+fn recorded_url(req: &ureq::Request, redacted: &[String]) -> String {
+    let Ok(url) = req.request_url() else {
+        return req.url().to_string();
+    };
+
+    let mut url = url.as_url().clone();
+    let pairs = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if redacted.iter().any(|r| *r == name) {
+                REDACTED.to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect::<Vec<_>>();
+    if pairs.iter().any(|(_, value)| value == REDACTED) {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url.to_string()
+}
+
+/// Send the request, and save the request and response to a file in `dir`.
+#[allow(clippy::result_large_err)]
+fn record(
+    dir: &Path,
+    req: ureq::Request,
+    body: Option<serde_json::Value>,
+    redacted: &[String],
+) -> Result<ureq::Response, ureq::Error> {
+    let method = req.method().to_string();
+    let url = recorded_url(&req, redacted);
+    let response = match body.as_ref() {
+        Some(body) => req.send_json(body),
+        None => req.call(),
+    };
+
+    // Transport errors have no response to record.
+    let response = match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(e),
+    };
+
+    let response = RecordedResponse::from_response(response)?;
+    let path = recording_path(dir, &method, &url, &body);
+    let recording = Recording {
+        method,
+        url,
+        body,
+        response,
+    };
+
+    let result = std::fs::create_dir_all(dir).and_then(|_| {
+        let contents = serde_json::to_string_pretty(&recording)?;
+        std::fs::write(&path, contents)
+    });
+    if let Err(e) = result {
+        event!(
+            Level::WARN,
+            path = %path.display(),
+            error = %e,
+            "Failed to write recording"
+        );
+    }
+
+    recording.response.to_response()
+}
+
+/// HTTP client settings for a host.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpOptions {
+    /// Seconds to wait for a connection to the host.
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for each read from the host. For streamed responses, this is the longest
+    /// time allowed between chunks.
+    pub read_timeout: Option<u64>,
+    /// Seconds to wait for the entire request, including reading the response.
+    pub total_timeout: Option<u64>,
+    /// The URL of a proxy server. If omitted, the `HTTPS_PROXY`, `HTTP_PROXY`, and `ALL_PROXY`
+    /// environment variables are used.
+    pub proxy: Option<String>,
+    /// A PEM file of additional certificate authorities to trust.
+    pub ca_cert: Option<PathBuf>,
+    /// Extra headers to send with each request. Values can reference environment variables as
+    /// `$NAME` or `${NAME}`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Extra query parameters to add to each request, which can also reference environment
+    /// variables.
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+}
+
+impl HttpOptions {
+    /// Overwrite these options with the values set in `other`. Returns the keys of the values
+    /// that were taken from `other`.
+    pub fn merge_from(&mut self, other: &HttpOptions) -> MergedKeys {
+        let mut merged = MergedKeys::default();
+        merged.overwrite_option_from_option(
+            "connect_timeout",
+            &mut self.connect_timeout,
+            &other.connect_timeout,
+        );
+        merged.overwrite_option_from_option(
+            "read_timeout",
+            &mut self.read_timeout,
+            &other.read_timeout,
+        );
+        merged.overwrite_option_from_option(
+            "total_timeout",
+            &mut self.total_timeout,
+            &other.total_timeout,
+        );
+        merged.overwrite_option_from_option("proxy", &mut self.proxy, &other.proxy);
+        merged.overwrite_option_from_option("ca_cert", &mut self.ca_cert, &other.ca_cert);
+
+        for (key, value) in &other.headers {
+            self.headers.insert(key.clone(), value.clone());
+            merged.add(&join_key("headers", key));
+        }
+        for (key, value) in &other.query {
+            self.query.insert(key.clone(), value.clone());
+            merged.add(&join_key("query", key));
+        }
+
+        merged
+    }
+
+    /// Create an HTTP client with these settings, for sending requests to `endpoint`.
+    pub fn build_client(&self, endpoint: &str) -> Result<HttpClient, Report<Error>> {
+        let mut builder = ureq::AgentBuilder::new();
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.timeout_connect(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.timeout_read(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.total_timeout {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+
+        if let Some(proxy) = self.proxy.as_deref() {
+            let proxy = ureq::Proxy::new(proxy)
+                .change_context(Error::HttpSettings)
+                .attach_printable_lazy(|| format!("Invalid proxy {proxy}"))?;
+            builder = builder.proxy(proxy);
+        } else if let Some(proxy) = env_proxy(endpoint, |name| std::env::var(name).ok()) {
+            // env_proxy only returns proxies that parse.
+            builder = builder.proxy(ureq::Proxy::new(proxy).expect("parsing proxy"));
+        }
+
+        if let Some(path) = self.ca_cert.as_deref() {
+            builder = builder.tls_config(Arc::new(tls_config_with_ca(path)?));
+        }
+
+        Ok(HttpClient {
+            agent: builder.build(),
+            headers: expand_map("header", &self.headers)?,
+            query: expand_map("query parameter", &self.query)?,
+        })
+    }
+}
+
+/// The environment variables that can set a proxy, in the order that ureq checks them.
+const PROXY_VARS: [&str; 6] = [
+    "ALL_PROXY",
+    "all_proxy",
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+];
+
+/// The proxy from the environment to use for requests to `endpoint`, if any, with `lookup`
+/// reading the environment variables. This follows ureq's `try_proxy_from_env`, and also checks
+/// `NO_PROXY`, which ureq doesn't read.
+fn env_proxy(endpoint: &str, lookup: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let no_proxy = lookup("NO_PROXY")
+        .or_else(|| lookup("no_proxy"))
+        .unwrap_or_default();
+    if bypasses_proxy(endpoint, &no_proxy) {
+        return None;
+    }
+
+    PROXY_VARS
+        .iter()
+        .filter_map(|var| lookup(var))
+        .find(|proxy| ureq::Proxy::new(proxy).is_ok())
+}
+
+/// Loopback hosts, such as a local Ollama server, are always contacted directly, as are hosts
+/// that match an entry in `no_proxy`. Each entry is a host name, which also matches its
+/// subdomains, or `*` to match every host.
+fn bypasses_proxy(endpoint: &str, no_proxy: &str) -> bool {
+    let Ok(url) = url::Url::parse(endpoint) else {
+        return false;
+    };
+
+    let host = match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.to_ascii_lowercase();
+            if domain == "localhost" || domain.ends_with(".localhost") {
+                return true;
+            }
+            domain
+        }
+        Some(url::Host::Ipv4(ip)) if ip.is_loopback() => return true,
+        Some(url::Host::Ipv6(ip)) if ip.is_loopback() => return true,
+        Some(host) => host.to_string(),
+        None => return false,
+    };
+
+    no_proxy
+        .split(',')
+        .map(|entry| {
+            entry
+                .trim()
+                .trim_start_matches("*.")
+                .trim_start_matches('.')
+        })
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*"
+                || host.eq_ignore_ascii_case(entry)
+                || host.ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+        })
+}
+
+/// Expand the environment variables in the values of a map.
+fn expand_map(
+    kind: &str,
+    map: &BTreeMap<String, String>,
+) -> Result<Vec<(String, String)>, Report<Error>> {
+    map.iter()
+        .map(|(name, value)| {
+            let value = expand_env_vars(value).map_err(|var| {
+                Report::new(Error::HttpSettings).attach_printable(format!(
+                    "Environment variable {var} is not set, for {kind} {name}"
+                ))
+            })?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+/// The HTTP client for a host, which adds the host's extra headers and query parameters to each
+/// request.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    agent: ureq::Agent,
+    headers: Vec<(String, String)>,
+    query: Vec<(String, String)>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self {
+            agent: ureq::Agent::new(),
+            headers: Vec::new(),
+            query: Vec::new(),
+        }
+    }
+}
+
+impl HttpClient {
+    /// Send a request with a JSON body. All HTTP requests to hosts should go through this or
+    /// [HttpClient::call], so that they can be recorded and replayed.
+    #[allow(clippy::result_large_err)]
+    pub fn send_json(
+        &self,
+        req: ureq::Request,
+        body: impl Serialize,
+    ) -> Result<ureq::Response, ureq::Error> {
+        let body = serde_json::to_value(body).map_err(|e| {
+            ureq::Error::from(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        })?;
+        self.send(req, Some(body))
+    }
+
+    /// Send a request without a body.
+    #[allow(clippy::result_large_err)]
+    pub fn call(&self, req: ureq::Request) -> Result<ureq::Response, ureq::Error> {
+        self.send(req, None)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn send(
+        &self,
+        req: ureq::Request,
+        body: Option<serde_json::Value>,
+    ) -> Result<ureq::Response, ureq::Error> {
+        match record_mode() {
+            RecordMode::Off => match body {
+                Some(body) => req.send_json(body),
+                None => req.call(),
+            },
+            RecordMode::Record(dir) => record(&dir, req, body, &self.query_names()),
+            RecordMode::Replay(dir) => replay(
+                &dir,
+                req.method(),
+                &recorded_url(&req, &self.query_names()),
+                &body,
+            ),
+        }
+    }
+
+    fn query_names(&self) -> Vec<String> {
+        self.query.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn get(&self, url: &str) -> ureq::Request {
+        self.request("GET", url)
+    }
+
+    pub fn post(&self, url: &str) -> ureq::Request {
+        self.request("POST", url)
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self
+            .headers
+            .iter()
+            .fold(self.agent.request(method, url), |request, (name, value)| {
+                request.set(name, value)
+            });
+        self.query
+            .iter()
+            .fold(request, |request, (name, value)| request.query(name, value))
+    }
+}
+
+/// Create a TLS configuration that trusts the certificates in the PEM file at `path`, in addition
+/// to the usual root certificates.
+fn tls_config_with_ca(path: &Path) -> Result<rustls::ClientConfig, Report<Error>> {
+    let file = std::fs::File::open(path)
+        .change_context(Error::HttpSettings)
+        .attach_printable_lazy(|| format!("Failed to read CA certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+        .change_context(Error::HttpSettings)
+        .attach_printable_lazy(|| format!("Failed to parse CA certificate {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(Report::new(Error::HttpSettings))
+            .attach_printable(format!("No certificates found in {}", path.display()));
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    for cert in certs {
+        roots
+            .add(&rustls::Certificate(cert))
+            .change_context(Error::HttpSettings)
+            .attach_printable_lazy(|| format!("Invalid CA certificate in {}", path.display()))?;
+    }
+
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// How to retry failed requests to a host.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryOptions {
+    /// The maximum number of times to send a request, including the first attempt.
+    pub max_attempts: u32,
+    /// The delay before the first retry. The delay doubles with each retry.
+    pub base_delay_ms: u64,
+    /// The longest time to wait between attempts, including delays requested by the host.
+    pub max_delay_ms: u64,
+    /// HTTP status codes that should be retried.
+    pub statuses: Vec<u16>,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            statuses: vec![429, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryOptions {
+    fn should_retry(&self, err: &ureq::Error) -> bool {
+        match err {
+            ureq::Error::Status(code, _) => self.statuses.contains(code),
+            // Connections that are reset or dropped are often temporary. A refused connection is
+            // not retried, since it usually means that the host isn't running.
+            ureq::Error::Transport(transport) => transport.kind() == ureq::ErrorKind::Io,
+        }
+    }
+
+    /// How long to wait before the next attempt. `retry` is 0 for the first retry.
+    fn delay(&self, retry: u32, response: Option<&ureq::Response>) -> Duration {
+        let delay = response.and_then(requested_delay).unwrap_or_else(|| {
+            let backoff = self
+                .base_delay_ms
+                .saturating_mul(2u64.saturating_pow(retry));
+            let jitter = fastrand::u64(0..=self.base_delay_ms / 10);
+            Duration::from_millis(backoff.saturating_add(jitter))
+        });
+
+        delay.min(Duration::from_millis(self.max_delay_ms))
+    }
+}
+
+/// Get the delay that the host asked for, from the `Retry-After` header or, failing that, the
+/// longest of the `x-ratelimit-reset-*` headers.
+fn requested_delay(response: &ureq::Response) -> Option<Duration> {
+    if let Some(retry_after) = response.header("retry-after") {
+        let retry_after = retry_after.trim();
+        if let Ok(seconds) = retry_after.parse::<f64>() {
+            return Duration::try_from_secs_f64(seconds).ok();
+        }
+
+        // Otherwise it's an HTTP date.
+        if let Ok(date) = chrono::DateTime::parse_from_rfc2822(retry_after) {
+            let delay = date.signed_duration_since(chrono::Utc::now());
+            return Some(delay.to_std().unwrap_or_default());
+        }
+    }
+
+    response
+        .headers_names()
+        .into_iter()
+        .filter(|name| name.starts_with("x-ratelimit-reset"))
+        .filter_map(|name| response.header(&name).and_then(parse_reset_duration))
+        .max()
+}
+
+/// Parse a rate limit reset time, which is either a number of seconds or a duration like `6m0s`
+/// or `250ms`.
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+
+    let mut seconds = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number = rest[..number_end].parse::<f64>().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let multiplier = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        seconds += number * multiplier;
+        rest = &rest[unit_end..];
+    }
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+// ureq's error is large, but the callers need the response it contains.
+#[allow(clippy::result_large_err)]
 pub fn request_with_retry(
+    client: &HttpClient,
     req: ureq::Request,
     body: impl Serialize,
+    retry: &RetryOptions,
 ) -> Result<ureq::Response, ureq::Error> {
-    const MAX_TRIES: u32 = 4;
-    let mut try_num = 0;
-    let delay = 1000;
+    retry_request(retry, || client.send_json(req.clone(), &body))
+}
+
+/// Send a request without a body, retrying according to the retry options.
+#[allow(clippy::result_large_err)]
+pub fn call_with_retry(
+    client: &HttpClient,
+    req: ureq::Request,
+    retry: &RetryOptions,
+) -> Result<ureq::Response, ureq::Error> {
+    retry_request(retry, || client.call(req.clone()))
+}
+
+/// Call `send` until it succeeds, retrying according to the retry options.
+#[allow(clippy::result_large_err)]
+pub fn retry_request<T>(
+    retry: &RetryOptions,
+    mut send: impl FnMut() -> Result<T, ureq::Error>,
+) -> Result<T, ureq::Error> {
+    let mut attempt = 1;
     loop {
-        let response = req.clone().send_json(&body);
-        match response {
+        let err = match send() {
             Ok(res) => return Ok(res),
-            Err(ureq::Error::Status(code, response)) => {
-                if code != 429 || try_num > MAX_TRIES {
-                    return Err(ureq::Error::Status(code, response));
-                }
+            Err(e) => e,
+        };
 
-                // This is potentially retryable. We don't do anything smart right now, just a
-                // random exponential backoff.
+        if attempt >= retry.max_attempts || !retry.should_retry(&err) {
+            return Err(err);
+        }
 
-                let perturb = fastrand::i32(-100..100);
-                let this_delay = 2i32.pow(try_num) * delay + perturb;
+        let (response, reason) = match &err {
+            ureq::Error::Status(code, response) => (Some(response), code.to_string()),
+            ureq::Error::Transport(transport) => (None, transport.to_string()),
+        };
+        let delay = retry.delay(attempt - 1, response);
+        event!(
+            Level::WARN,
+            attempt,
+            max_attempts = retry.max_attempts,
+            delay_ms = delay.as_millis() as u64,
+            reason = %reason,
+            "Request failed, retrying"
+        );
 
-                eprintln!("Rate limited... waiting {this_delay}ms to retry");
-                std::thread::sleep(std::time::Duration::from_millis(this_delay as u64));
-                try_num += 1;
-            }
-            e @ Err(_) => return e,
-        }
+        std::thread::sleep(delay);
+        attempt += 1;
     }
 }
 
@@ -38,3 +733,476 @@ pub fn add_bearer_token(req: ureq::Request, token: &Option<String>) -> ureq::Req
         req
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::result_large_err)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{error::error_message, tests::base_dir};
+
+    const OPENAI: &str = "https://api.openai.com/v1";
+
+    /// Start a server that answers one request with `response`, and return its URL.
+    fn serve_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // Read the whole request so that the connection closes cleanly.
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(length) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let url = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nX-Request-Id: abc\r\n\r\n{\"response\":\"one\"}\n{\"response\":\"two\"}\n",
+        );
+        let url = format!("{url}/api/generate");
+        let dir = tempfile::tempdir().unwrap();
+
+        let body = json!({ "model": "a", "options": { "temperature": 0.5, "top_k": 4 } });
+        let response = record(dir.path(), ureq::post(&url), Some(body.clone()), &[]).unwrap();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "{\"response\":\"one\"}\n{\"response\":\"two\"}\n"
+        );
+
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, 1);
+
+        // The key order of the body doesn't matter when matching.
+        let reordered: serde_json::Value = serde_json::from_str(
+            r#"{ "options": { "top_k": 4, "temperature": 0.5 }, "model": "a" }"#,
+        )
+        .unwrap();
+        let response = replay(dir.path(), "POST", &url, &Some(reordered)).unwrap();
+        assert_eq!(response.header("x-request-id"), Some("abc"));
+        assert_eq!(
+            response.into_string().unwrap(),
+            "{\"response\":\"one\"}\n{\"response\":\"two\"}\n"
+        );
+    }
+
+    #[test]
+    fn record_redacts_query() {
+        let url = serve_once("HTTP/1.1 200 OK\r\n\r\n{}");
+        let url = format!("{url}/v1/models?existing=1");
+        let dir = tempfile::tempdir().unwrap();
+
+        std::env::set_var("PROMPTBOX_TEST_QUERY_KEY", "secret-key");
+        let client = HttpOptions {
+            query: [("key".to_string(), "${PROMPTBOX_TEST_QUERY_KEY}".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+        .build_client(&url)
+        .unwrap();
+
+        let req = client.get(&url);
+        record(dir.path(), req.clone(), None, &client.query_names()).unwrap();
+
+        let path = std::fs::read_dir(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert!(!contents.contains("secret-key"), "{contents}");
+        assert!(contents.contains("existing=1&key=REDACTED"), "{contents}");
+
+        let response = with_replay(dir.path().to_path_buf(), || client.call(req)).unwrap();
+        assert_eq!(response.into_string().unwrap(), "{}");
+    }
+
+    #[test]
+    fn record_error_status() {
+        let url = serve_once("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 2\r\n\r\nslow down");
+        let url = format!("{url}/api/generate");
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = record(dir.path(), ureq::get(&url), None, &[]).unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(429, _)));
+
+        let Err(ureq::Error::Status(429, response)) = replay(dir.path(), "GET", &url, &None) else {
+            panic!("Expected a 429 response");
+        };
+        assert_eq!(response.header("retry-after"), Some("2"));
+        assert_eq!(response.into_string().unwrap(), "slow down");
+    }
+
+    fn response(raw: &str) -> ureq::Response {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn reset_durations() {
+        assert_eq!(parse_reset_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(
+            parse_reset_duration("0.5"),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            parse_reset_duration("250ms"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset_duration("1h2m3.5s"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_reset_duration("soon"), None);
+        assert_eq!(parse_reset_duration("5x"), None);
+    }
+
+    #[test]
+    fn delay_from_headers() {
+        let retry_after = response("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3\r\n\r\n");
+        assert_eq!(requested_delay(&retry_after), Some(Duration::from_secs(3)));
+
+        let past_date = response(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n",
+        );
+        assert_eq!(requested_delay(&past_date), Some(Duration::ZERO));
+
+        let rate_limit = response(
+            "HTTP/1.1 429 Too Many Requests\r\nx-ratelimit-reset-requests: 1s\r\nx-ratelimit-reset-tokens: 6m0s\r\n\r\n",
+        );
+        assert_eq!(requested_delay(&rate_limit), Some(Duration::from_secs(360)));
+
+        let none = response("HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        assert_eq!(requested_delay(&none), None);
+    }
+
+    #[test]
+    fn delay_limits() {
+        let options = RetryOptions {
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            ..Default::default()
+        };
+
+        let first = options.delay(0, None);
+        assert!(first >= Duration::from_millis(100) && first <= Duration::from_millis(110));
+        let third = options.delay(2, None);
+        assert!(third >= Duration::from_millis(400) && third <= Duration::from_millis(410));
+        assert_eq!(options.delay(10, None), Duration::from_millis(1000));
+
+        let rate_limit = response("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\n\r\n");
+        assert_eq!(
+            options.delay(0, Some(&rate_limit)),
+            Duration::from_millis(1000)
+        );
+    }
+
+    fn fast_retry() -> RetryOptions {
+        RetryOptions {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let mut attempts = 0;
+        let result = retry_request(&fast_retry(), || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(ureq::Error::Status(
+                    503,
+                    response("HTTP/1.1 503 Service Unavailable\r\n\r\n"),
+                ))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut attempts = 0;
+        let result = retry_request(&fast_retry(), || {
+            attempts += 1;
+            Err::<(), _>(ureq::Error::Status(
+                502,
+                response("HTTP/1.1 502 Bad Gateway\r\n\r\n"),
+            ))
+        });
+        assert!(matches!(result, Err(ureq::Error::Status(502, _))));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let mut attempts = 0;
+        let result = retry_request(&fast_retry(), || {
+            attempts += 1;
+            Err::<(), _>(ureq::Error::Status(
+                400,
+                response("HTTP/1.1 400 Bad Request\r\n\r\n"),
+            ))
+        });
+        assert!(matches!(result, Err(ureq::Error::Status(400, _))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn retries_dropped_connections() {
+        // A server that closes every connection without responding.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        });
+
+        let mut attempts = 0;
+        let result = retry_request(&fast_retry(), || {
+            attempts += 1;
+            ureq::get(&url).call()
+        });
+        assert!(matches!(result, Err(ureq::Error::Transport(_))));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn does_not_retry_refused_connections() {
+        // Find a port with nothing listening on it.
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+
+        let mut attempts = 0;
+        let result = retry_request(&fast_retry(), || {
+            attempts += 1;
+            ureq::get(&url).call()
+        });
+        assert!(matches!(result, Err(ureq::Error::Transport(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn partial_retry_config() {
+        let options: RetryOptions = toml::from_str("max_attempts = 2").unwrap();
+        assert_eq!(
+            options,
+            RetryOptions {
+                max_attempts: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn read_timeout() {
+        // A server that accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let options = HttpOptions {
+            read_timeout: Some(1),
+            ..Default::default()
+        };
+        let client = options.build_client(&url).unwrap();
+        let start = std::time::Instant::now();
+        let err = client.get(&url).call().unwrap_err();
+        assert!(matches!(err, ureq::Error::Transport(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn extra_headers_and_query() {
+        // A server that responds with the request it received.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/models?existing=1",
+            listener.local_addr().unwrap()
+        );
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                head.push_str(&line);
+                line.clear();
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{head}",
+                head.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        std::env::set_var("PROMPTBOX_TEST_TENANT", "acme");
+        let options = HttpOptions {
+            headers: [
+                ("X-Title".to_string(), "PromptBox".to_string()),
+                (
+                    "X-Tenant".to_string(),
+                    "${PROMPTBOX_TEST_TENANT}".to_string(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            query: [("api-version".to_string(), "2024-02-01".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let client = options.build_client(&url).unwrap();
+        let request = client.get(&url).call().unwrap().into_string().unwrap();
+
+        assert!(
+            request.starts_with("GET /models?existing=1&api-version=2024-02-01 HTTP/1.1"),
+            "{request}"
+        );
+        assert!(request.contains("X-Title: PromptBox\r\n"), "{request}");
+        assert!(request.contains("X-Tenant: acme\r\n"), "{request}");
+    }
+
+    #[test]
+    fn missing_header_variable() {
+        let options = HttpOptions {
+            headers: [(
+                "X-Tenant".to_string(),
+                "${PROMPTBOX_TEST_UNSET_VAR}".to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let err = options.build_client(OPENAI).unwrap_err();
+        assert_eq!(
+            error_message(&err),
+            "Environment variable PROMPTBOX_TEST_UNSET_VAR is not set, for header X-Tenant: Invalid HTTP settings"
+        );
+    }
+
+    #[test]
+    fn invalid_proxy() {
+        let options = HttpOptions {
+            proxy: Some("ftp://proxy.example.com".to_string()),
+            ..Default::default()
+        };
+        let err = error_message(&options.build_client(OPENAI).unwrap_err());
+        assert!(err.starts_with("Invalid proxy"), "{err}");
+    }
+
+    #[test]
+    fn custom_ca() {
+        let options = HttpOptions {
+            ca_cert: Some(base_dir("http/ca.pem")),
+            ..Default::default()
+        };
+        options.build_client(OPENAI).expect("building client");
+
+        let options = HttpOptions {
+            ca_cert: Some(base_dir("http/missing.pem")),
+            ..Default::default()
+        };
+        let err = error_message(&options.build_client(OPENAI).unwrap_err());
+        assert!(err.starts_with("Failed to read CA certificate"), "{err}");
+
+        let options = HttpOptions {
+            ca_cert: Some(base_dir("test1.txt")),
+            ..Default::default()
+        };
+        let err = error_message(&options.build_client(OPENAI).unwrap_err());
+        assert!(err.starts_with("No certificates found"), "{err}");
+    }
+
+    #[test]
+    fn no_proxy() {
+        assert!(bypasses_proxy("http://localhost:11434", ""));
+        assert!(bypasses_proxy("http://127.0.0.1:1234", ""));
+        assert!(bypasses_proxy("http://[::1]:8080/v1", ""));
+        assert!(!bypasses_proxy(OPENAI, ""));
+        assert!(!bypasses_proxy(OPENAI, "example.com, openai.co"));
+        assert!(bypasses_proxy(OPENAI, "example.com, openai.com"));
+        assert!(bypasses_proxy(OPENAI, ".openai.com"));
+        assert!(bypasses_proxy(OPENAI, "API.OpenAI.com"));
+        assert!(bypasses_proxy(OPENAI, "*"));
+    }
+
+    #[test]
+    fn proxy_from_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        let proxies = env(&[
+            ("HTTPS_PROXY", "http://https-proxy:3128"),
+            ("http_proxy", "http://http-proxy:3128"),
+        ]);
+        assert_eq!(
+            env_proxy(OPENAI, proxies).as_deref(),
+            Some("http://https-proxy:3128")
+        );
+        assert_eq!(env_proxy("http://localhost:11434", proxies), None);
+        assert_eq!(env_proxy(OPENAI, env(&[])), None);
+
+        let with_no_proxy = env(&[
+            ("ALL_PROXY", "http://all-proxy:3128"),
+            ("no_proxy", "openai.com"),
+        ]);
+        assert_eq!(env_proxy(OPENAI, with_no_proxy), None);
+        assert_eq!(
+            env_proxy("https://api.together.xyz", with_no_proxy).as_deref(),
+            Some("http://all-proxy:3128")
+        );
+    }
+
+    #[test]
+    fn replay_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = replay(
+            dir.path(),
+            "POST",
+            "http://localhost/api/generate",
+            &Some(json!({ "model": "a" })),
+        )
+        .unwrap_err();
+        let ureq::Error::Status(404, response) = err else {
+            panic!("Expected a 404 response");
+        };
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("POST http://localhost/api/generate"));
+    }
+}
@@ -0,0 +1,139 @@
+use error_stack::{Report, ResultExt};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// A `[[route]]` entry in the configuration, which sends models matching a pattern to a host.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteInput {
+    /// A glob pattern matched against the model name, or a regular expression if it starts
+    /// with `^`.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub host: String,
+}
+
+/// A rule that chooses the host for a model name.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub pattern: String,
+    pub host: String,
+    /// True for the routes that PromptBox always has, after any configured routes.
+    pub builtin: bool,
+    regex: Regex,
+}
+
+impl Route {
+    pub fn new(pattern: &str, host: &str, builtin: bool) -> Result<Self, Report<Error>> {
+        let regex = if pattern.starts_with('^') {
+            pattern.to_string()
+        } else {
+            glob_to_regex(pattern)
+        };
+
+        let regex = Regex::new(&regex)
+            .change_context(Error::ParseConfig)
+            .attach_printable_lazy(|| format!("Invalid route pattern {pattern}"))?;
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            host: host.to_string(),
+            builtin,
+            regex,
+        })
+    }
+
+    pub fn matches(&self, model: &str) -> bool {
+        self.regex.is_match(model)
+    }
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.builtin {
+            write!(f, "built-in route `{}`", self.pattern)
+        } else {
+            write!(f, "route `{}`", self.pattern)
+        }
+    }
+}
+
+impl TryFrom<&RouteInput> for Route {
+    type Error = Report<Error>;
+
+    fn try_from(value: &RouteInput) -> Result<Self, Self::Error> {
+        Self::new(&value.pattern, &value.host, false)
+    }
+}
+
+/// Convert a glob pattern, where `*` matches any text and `?` matches one character, to a regular
+/// expression that matches the whole model name.
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The routes that apply after any configured routes.
+pub fn builtin_routes() -> Vec<Route> {
+    [
+        ("gpt-4*", "openai"),
+        ("gpt-3.5-*", "openai"),
+        ("lm-studio", "lm-studio"),
+    ]
+    .into_iter()
+    .map(|(pattern, host)| Route::new(pattern, host, true).expect("valid builtin route"))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        let route = Route::new("claude-*", "anthropic", false).unwrap();
+        assert!(route.matches("claude-3-opus"));
+        assert!(!route.matches("my-claude-3"));
+
+        let route = Route::new("llama?-*.gguf", "local", false).unwrap();
+        assert!(route.matches("llama2-7b.gguf"));
+        assert!(!route.matches("llama2-7bxgguf"));
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let route = Route::new("^mistralai/", "together", false).unwrap();
+        assert!(route.matches("mistralai/Mixtral-8x7B-Instruct-v0.1"));
+        assert!(!route.matches("mistral"));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let err = Route::new("^(unclosed", "together", false).unwrap_err();
+        assert!(matches!(err.current_context(), Error::ParseConfig));
+    }
+
+    #[test]
+    fn builtin() {
+        let routes = builtin_routes();
+        let host = |model: &str| {
+            routes
+                .iter()
+                .find(|r| r.matches(model))
+                .map(|r| r.host.as_str())
+        };
+        assert_eq!(host("gpt-4-1106-preview"), Some("openai"));
+        assert_eq!(host("gpt-3.5-turbo"), Some("openai"));
+        assert_eq!(host("lm-studio"), Some("lm-studio"));
+        assert_eq!(host("mistral"), None);
+    }
+}
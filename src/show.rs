@@ -0,0 +1,103 @@
+use std::{io::Write, path::PathBuf};
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+
+use crate::{config::Config, error::Error, template::ParsedTemplate};
+
+/// Print a template's description, the models it will use and how their hosts were chosen, and
+/// its options.
+pub fn show_template(
+    base_dir: PathBuf,
+    name: &str,
+    profile: Option<&str>,
+    output: &mut impl Write,
+) -> Result<(), Report<Error>> {
+    let config = Config::from_directory_with_profile(base_dir, profile)?;
+    let ParsedTemplate {
+        input,
+        definition_path,
+        ..
+    } = config.find_template(name)?;
+
+    let mut model_options = config.model;
+    model_options.update_from_model_input(&input.model);
+
+    let mut lines = vec![
+        format!("Template: {name}"),
+        format!("Path: {}", definition_path.display()),
+    ];
+    if !input.description.is_empty() {
+        lines.push(format!("Description: {}", input.description));
+    }
+
+    lines.push(String::new());
+    for (i, model) in model_options.model_chain().iter().enumerate() {
+        let label = if i == 0 { "Model" } else { "Fallback" };
+        let (host, choice) = model.host_choice();
+        lines.push(format!(
+            "{label}: {} on {host} ({choice})",
+            model.full_model_spec().model_name()
+        ));
+    }
+
+    if !input.options.is_empty() {
+        lines.push(String::new());
+        lines.push("Options:".to_string());
+        for (name, option) in input.options.iter().sorted_by_key(|(name, _)| *name) {
+            let mut details = vec![format!("{:?}", option.option_type).to_lowercase()];
+            if option.array {
+                details.push("array".to_string());
+            }
+            if let Some(default) = option.default.as_ref() {
+                details.push(format!("default {default}"));
+            } else if !option.is_required() {
+                details.push("optional".to_string());
+            }
+
+            let mut line = format!("  --{name} ({})", details.join(", "));
+            if !option.description.is_empty() {
+                line.push_str(": ");
+                line.push_str(&option.description);
+            }
+            lines.push(line);
+        }
+    }
+
+    for line in lines {
+        writeln!(output, "{line}").change_context(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::show_template;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn show_routes() {
+        let mut output = Vec::new();
+        show_template(base_dir(""), "routed", None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "Template: routed");
+        assert_eq!(lines[2], "Description: Models chosen by routes");
+        assert_eq!(
+            &lines[4..],
+            &[
+                "Model: routed/small on mock (matched route `^routed/`)",
+                "Fallback: gpt-4-mock on mock (matched route `gpt-4-mock*`)",
+                "Fallback: gpt-4 on openai (matched built-in route `gpt-4*`)",
+                "Fallback: mistral on my_ollama_host (default host)",
+                "Fallback: llama2 on together (set by the model)",
+                "",
+                "Options:",
+                "  --count (integer, default 3): How many",
+                "  --topic (string): The topic",
+            ]
+        );
+    }
+}
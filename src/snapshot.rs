@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    args::SnapshotArgs, config::Config, error::error_message, error::Error,
+    template::ParsedTemplate,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SnapshotStatus {
+    /// The rendered prompts match the snapshot
+    Unchanged,
+    /// There was no snapshot file yet
+    New,
+    /// The rendered prompts differ from the snapshot
+    Changed { diff: String },
+    /// The prompts could not be rendered
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct SnapshotResult {
+    pub template: String,
+    pub path: PathBuf,
+    pub status: SnapshotStatus,
+}
+
+/// Get the path of the snapshot file for a template, which sits beside the template file.
+fn snapshot_path(template: &ParsedTemplate) -> PathBuf {
+    let file_name = template
+        .definition_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let stem = file_name.strip_suffix(".pb.toml").unwrap_or(&file_name);
+    template
+        .definition_path
+        .with_file_name(format!("{stem}.snap"))
+}
+
+/// Render the template with the inputs from each of its tests, and format the results as the
+/// contents of a snapshot file. Returns `None` if the template has no tests.
+fn render_snapshot(
+    base_dir: &Path,
+    template_name: &str,
+    template: &ParsedTemplate,
+) -> Result<Option<String>, Report<Error>> {
+    if template.input.tests.is_empty() {
+        return Ok(None);
+    }
+
+    let template_dir = template
+        .definition_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let mut output = String::new();
+    for (i, test) in template.input.tests.iter().enumerate() {
+        let name = test.display_name(i);
+        let (_, _, prompt, system, _) = test
+            .generate(base_dir.to_path_buf(), template_name, template_dir.clone())
+            .attach_printable_lazy(|| format!("Test {name}"))?;
+
+        output.push_str(&format!("=== {name} ===\n"));
+        if !system.is_empty() {
+            output.push_str(&format!("--- system ---\n{}\n", system.trim_end()));
+        }
+        output.push_str(&format!("--- prompt ---\n{}\n\n", prompt.trim_end()));
+    }
+
+    Ok(Some(output))
+}
+
+/// Create a unified diff between the existing snapshot and the newly rendered one.
+fn snapshot_diff(path: &Path, old: &str, new: &str) -> String {
+    let path = path.display().to_string();
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&path, "rendered")
+        .to_string()
+}
+
+/// Compare each template against its snapshot, writing new snapshots if `args.update` is set.
+pub fn collect_snapshots(
+    base_dir: PathBuf,
+    args: &SnapshotArgs,
+) -> Result<Vec<SnapshotResult>, Report<Error>> {
+    let config = Config::from_directory(base_dir.clone())?;
+    let template_names = if args.templates.is_empty() {
+        config.list_templates()
+    } else {
+        args.templates.clone()
+    };
+
+    let mut results = vec![];
+    for template_name in template_names {
+        let template = match config.find_template(&template_name) {
+            Ok(template) => template,
+            Err(e) => {
+                results.push(SnapshotResult {
+                    template: template_name,
+                    path: PathBuf::new(),
+                    status: SnapshotStatus::Failed(error_message(&e)),
+                });
+                continue;
+            }
+        };
+
+        let path = snapshot_path(&template);
+        let rendered = match render_snapshot(&base_dir, &template_name, &template) {
+            Ok(Some(rendered)) => rendered,
+            Ok(None) => continue,
+            Err(e) => {
+                results.push(SnapshotResult {
+                    template: template_name,
+                    path,
+                    status: SnapshotStatus::Failed(error_message(&e)),
+                });
+                continue;
+            }
+        };
+
+        let status = match std::fs::read_to_string(&path) {
+            Ok(existing) if existing == rendered => SnapshotStatus::Unchanged,
+            Ok(existing) => SnapshotStatus::Changed {
+                diff: snapshot_diff(&path, &existing, &rendered),
+            },
+            Err(_) => SnapshotStatus::New,
+        };
+
+        if args.update && status != SnapshotStatus::Unchanged {
+            std::fs::write(&path, &rendered)
+                .change_context(Error::Io)
+                .attach_printable_lazy(|| format!("Writing {}", path.display()))?;
+        }
+
+        results.push(SnapshotResult {
+            template: template_name,
+            path,
+            status,
+        });
+    }
+
+    Ok(results)
+}
+
+/// Check the snapshots, print a report, and return an error if any did not match.
+pub fn run_snapshots(base_dir: PathBuf, args: SnapshotArgs) -> Result<(), Report<Error>> {
+    let results = collect_snapshots(base_dir, &args)?;
+
+    if results.is_empty() {
+        println!("No templates with tests found");
+        return Ok(());
+    }
+
+    let mut mismatched = 0;
+    for result in &results {
+        match &result.status {
+            SnapshotStatus::Unchanged => println!("ok      {}", result.template),
+            SnapshotStatus::New if args.update => {
+                println!("created {}: {}", result.template, result.path.display())
+            }
+            SnapshotStatus::New => {
+                mismatched += 1;
+                println!(
+                    "missing {}: run with --update to create {}",
+                    result.template,
+                    result.path.display()
+                );
+            }
+            SnapshotStatus::Changed { diff } => {
+                let status = if args.update { "updated" } else { "changed" };
+                println!("{status} {}\n{diff}", result.template);
+                if !args.update {
+                    mismatched += 1;
+                }
+            }
+            SnapshotStatus::Failed(message) => {
+                mismatched += 1;
+                println!("error   {}: {message}", result.template);
+            }
+        }
+    }
+
+    if mismatched > 0 {
+        return Err(Report::new(Error::SnapshotMismatch)).attach_printable(format!(
+            "{mismatched} of {} snapshots did not match",
+            results.len()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::base_dir;
+
+    #[test]
+    fn unchanged_snapshot() {
+        let args = SnapshotArgs {
+            templates: vec!["snapshot".to_string()],
+            update: false,
+        };
+
+        let results = collect_snapshots(base_dir(""), &args).expect("checking snapshots");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, SnapshotStatus::Unchanged);
+        assert_eq!(results[0].path, base_dir("snapshot.snap"));
+    }
+
+    #[test]
+    fn skips_templates_without_tests() {
+        let args = SnapshotArgs {
+            templates: vec!["vars".to_string()],
+            update: false,
+        };
+
+        let results = collect_snapshots(base_dir(""), &args).expect("checking snapshots");
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn diff() {
+        let diff = snapshot_diff(
+            Path::new("test.snap"),
+            "=== a ===\n--- prompt ---\nold line\n",
+            "=== a ===\n--- prompt ---\nnew line\n",
+        );
+        assert_eq!(
+            diff,
+            "--- test.snap\n+++ rendered\n@@ -1,3 +1,3 @@\n === a ===\n --- prompt ---\n-old line\n+new line\n"
+        );
+    }
+}
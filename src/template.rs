@@ -8,7 +8,14 @@ use error_stack::{Report, ResultExt};
 use serde::Deserialize;
 use tera::Tera;
 
-use crate::{args::GlobalRunArgs, error::Error, model::ModelOptionsInput};
+use crate::{
+    args::GlobalRunArgs,
+    editor::edit_text,
+    error::Error,
+    model::ModelOptionsInput,
+    provenance::{Origin, Provenance},
+    testing::TemplateTest,
+};
 
 #[derive(Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
@@ -22,6 +29,24 @@ pub enum OptionType {
     Bool,
     File,
     Image,
+    /// A JSON file, parsed and passed to the template as structured data
+    Json,
+    /// A TOML file, parsed and passed to the template as structured data
+    Toml,
+    /// A YAML file, parsed and passed to the template as structured data
+    Yaml,
+    /// A CSV file, passed to the template as an array of objects keyed by the header row
+    Csv,
+}
+
+impl OptionType {
+    /// Returns true if values for this option are paths to files.
+    pub fn is_path(&self) -> bool {
+        matches!(
+            self,
+            Self::File | Self::Image | Self::Json | Self::Toml | Self::Yaml | Self::Csv
+        )
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,6 +63,17 @@ pub struct PromptOption {
     /// Set `optional` true to allow omitting the option without providing a default value
     #[serde(default)]
     pub optional: bool,
+    /// If true, content piped into stdin is used as the value for this option, instead of being
+    /// treated as extra content.
+    #[serde(default)]
+    pub stdin: bool,
+}
+
+impl PromptOption {
+    /// Returns true if a value must be provided for this option.
+    pub fn is_required(&self) -> bool {
+        self.option_type != OptionType::Bool && self.default.is_none() && !self.optional
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -55,15 +91,23 @@ pub struct PromptTemplate {
 
     pub template: Option<String>,
     pub template_path: Option<PathBuf>,
+
+    /// Tests to run with `promptbox test`
+    #[serde(default)]
+    pub tests: Vec<TemplateTest>,
 }
 
 #[derive(Debug)]
 pub struct ParsedTemplate {
     pub name: String,
     pub input: PromptTemplate,
+    /// The path to the template definition file
+    pub definition_path: PathBuf,
     pub path: PathBuf,
     pub template: String,
     pub system: Option<(PathBuf, String)>,
+    /// Where each value in the template's `model` section was set
+    pub provenance: Provenance,
 }
 
 impl ParsedTemplate {
@@ -73,10 +117,20 @@ impl ParsedTemplate {
             return Ok(None);
         };
 
+        let table: toml::Table = toml::from_str(&contents)
+            .change_context(Error::ParseTemplate)
+            .attach_printable_lazy(|| path.display().to_string())?;
         let mut prompt_template: PromptTemplate = toml::from_str(&contents)
             .change_context(Error::ParseTemplate)
             .attach_printable_lazy(|| path.display().to_string())?;
 
+        let provenance = match table.get("model") {
+            Some(toml::Value::Table(model)) => {
+                Provenance::from_table("model", model, &Origin::File(path.to_path_buf()))
+            }
+            _ => Provenance::default(),
+        };
+
         // At some point we should support partials here, but it still needs some design since we
         // want to allow templates to reference partials in upper directories. For now, we just
         // do a String.
@@ -121,9 +175,11 @@ impl ParsedTemplate {
         Ok(Some(ParsedTemplate {
             name: name.to_string(),
             input: prompt_template,
+            definition_path: path.to_path_buf(),
             path: template_path,
             template: template_result,
             system,
+            provenance,
         }))
     }
 }
@@ -156,7 +212,8 @@ pub fn assemble_template(
     let mut extra = std::mem::take(&mut args.extra_prompt);
 
     let stdin = std::io::stdin();
-    if !stdin.is_terminal() {
+    // When stdin was used for option values, it can't also be used for extra content.
+    if !args.no_stdin && !args.vars_stdin && !args.stdin_consumed && !stdin.is_terminal() {
         // Some text is potentially being piped in, so read it.
         let stdin_value = std::io::read_to_string(stdin)
             .attach_printable("Reading stdin")
@@ -167,6 +224,12 @@ pub fn assemble_template(
     };
 
     let extra_content = extra.join("\n\n");
+    let extra_content = if args.edit {
+        edit_extra_content(&template, template_context, &extra_content)?
+    } else {
+        extra_content
+    };
+
     if template_references_extra(&template) {
         template_context["extra"] = extra_content.into();
     } else if !extra_content.is_empty() {
@@ -181,6 +244,86 @@ pub fn assemble_template(
     Ok(template)
 }
 
+const EDIT_SCISSORS: &str = "# ------------------------ >8 ------------------------";
+const EDIT_EXTRA_MARKER: &str = "[your text]";
+
+/// Open the user's editor to write the extra content for the prompt, starting with `initial`.
+fn edit_extra_content(
+    template: &str,
+    template_context: &serde_json::Value,
+    initial: &str,
+) -> Result<String, Report<Error>> {
+    let mut preview_context = template_context.clone();
+    preview_context["extra"] = EDIT_EXTRA_MARKER.into();
+    let preview = tera::Context::from_value(preview_context)
+        .ok()
+        .and_then(|context| Tera::one_off(template, &context, false).ok())
+        .unwrap_or_else(|| template.to_string());
+
+    let edited = edit_text(&create_edit_file(&preview, initial))?;
+    let content = parse_edit_file(&edited, &preview);
+    if content.is_empty() {
+        return Err(Report::new(Error::Canceled)).attach_printable("The extra content was empty");
+    }
+
+    Ok(content)
+}
+
+/// Create the contents of the file used to edit extra content. The rendered prompt is shown as a
+/// commented header, separated from the content by a scissors line.
+fn create_edit_file(preview: &str, initial: &str) -> String {
+    let mut contents = edit_file_header(preview).join("\n");
+    contents.push('\n');
+    contents.push_str(EDIT_SCISSORS);
+    contents.push('\n');
+    contents.push_str(initial);
+    contents
+}
+
+/// The commented lines above the scissors line in the edit file.
+fn edit_file_header(preview: &str) -> Vec<String> {
+    let mut lines = [
+        "# Write the text to add to the prompt below the scissors line.",
+        "# Everything above it is ignored. Save an empty file to cancel.",
+        "#",
+        "# Rendered prompt:",
+        "#",
+    ]
+    .map(String::from)
+    .to_vec();
+
+    lines.extend(
+        preview
+            .lines()
+            .map(|line| format!("# {line}").trim_end().to_string()),
+    );
+    lines
+}
+
+/// Extract the user's content from an edited file. The content starts after the last scissors
+/// line. If the scissors line was removed, the header lines that [create_edit_file] generated for
+/// `preview` are dropped instead, so that they aren't added to the prompt.
+fn parse_edit_file(contents: &str, preview: &str) -> String {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let content = match lines
+        .iter()
+        .rposition(|line| line.trim_end() == EDIT_SCISSORS)
+    {
+        Some(scissors) => lines[scissors + 1..].join("\n"),
+        None => {
+            // Match the generated lines in order, so lines after the header are always kept.
+            let mut header = edit_file_header(preview).into_iter().peekable();
+            lines
+                .into_iter()
+                .filter(|line| header.next_if(|h| h == line.trim_end()).is_none())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    };
+
+    content.trim().to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use std::{ffi::OsString, path::PathBuf};
@@ -341,14 +484,6 @@ optvalue
         let options = template.input.model;
 
         assert_eq!(options.model, Some("abc".to_string().into()));
-        assert_eq!(
-            options.lm_studio_host,
-            Some("http://localhost:9998".to_string())
-        );
-        assert_eq!(
-            options.ollama_host,
-            Some("http://localhost:9999".to_string())
-        );
         assert_eq!(options.temperature, Some(0.3));
         assert_eq!(options.format, Some(crate::model::OutputFormat::JSON));
         assert_eq!(options.top_p, Some(0.5));
@@ -785,6 +920,214 @@ test1.txt: test1
             let result = generate_template(BASE_DIR.into(), "images".to_string(), cmdline);
             let _ = result.expect_err("should have been an error");
         }
+
+        #[test]
+        fn structured_data_args() {
+            let cmdline = to_cmdline_vec(vec![
+                "test",
+                "run",
+                "structured_data",
+                "--json_data",
+                "data/people.json",
+                "--toml_data",
+                "data/people.toml",
+                "--yaml_data",
+                "data/people.yaml",
+                "--csv_data",
+                "data/people.csv",
+            ]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "structured_data".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "Alice: engineer\nBob: designer\n".repeat(4));
+        }
+
+        #[test]
+        fn structured_data_parse_failure() {
+            let cmdline = to_cmdline_vec(vec![
+                "test",
+                "run",
+                "structured_data",
+                "--json_data",
+                "data/malformed.json",
+            ]);
+
+            let err = generate_template(BASE_DIR.into(), "structured_data".to_string(), cmdline)
+                .expect_err("should have been an error");
+            assert!(matches!(err.current_context(), Error::ArgParseFailure));
+
+            let message = format!("{err:?}");
+            assert!(
+                message.contains("data/malformed.json:3"),
+                "error should include the file and line: {message}"
+            );
+        }
+    }
+
+    mod vars {
+        use super::*;
+
+        #[test]
+        fn json_file() {
+            let cmdline = to_cmdline_vec(vec!["test", "run", "vars", "--vars", "vars/values.json"]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "from_json 3 true a,b\nnotes.txt: some notes");
+        }
+
+        #[test]
+        fn toml_file() {
+            let cmdline = to_cmdline_vec(vec!["test", "run", "vars", "--vars", "vars/values.toml"]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "from_toml 4 false c\nnotes.txt: some notes");
+        }
+
+        #[test]
+        fn cmdline_overrides_vars() {
+            let cmdline = to_cmdline_vec(vec![
+                "test",
+                "run",
+                "vars",
+                "--vars",
+                "vars/values.json",
+                "--name",
+                "from_cmdline",
+                "--tags",
+                "x",
+                "--notes",
+                "test1.txt",
+            ]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "from_cmdline 3 true x\ntest1.txt: test1\n");
+        }
+
+        #[test]
+        fn cmdline_turns_off_preset_flag() {
+            let cmdline = to_cmdline_vec(vec![
+                "test",
+                "run",
+                "vars",
+                "--vars",
+                "vars/values.json",
+                "--enabled=false",
+            ]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "from_json 3 false a,b\nnotes.txt: some notes");
+
+            let cmdline = to_cmdline_vec(vec![
+                "test",
+                "run",
+                "vars",
+                "--vars",
+                "vars/values.toml",
+                "--enabled",
+            ]);
+
+            let (_, _, prompt, _, _) =
+                generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                    .expect("generate_template");
+            assert_eq!(prompt, "from_toml 4 true c\nnotes.txt: some notes");
+        }
+
+        #[test]
+        fn bad_type() {
+            let cmdline =
+                to_cmdline_vec(vec!["test", "run", "vars", "--vars", "vars/bad_type.json"]);
+
+            let err = generate_template(BASE_DIR.into(), "vars".to_string(), cmdline)
+                .expect_err("should have been an error");
+            assert!(matches!(
+                err.current_context(),
+                Error::CmdlineParseFailure(_)
+            ));
+        }
+    }
+
+    mod edit_extra {
+        use super::super::{create_edit_file, parse_edit_file, EDIT_SCISSORS};
+
+        #[test]
+        fn round_trip() {
+            let preview = "Summarize this:\n\n[your text]";
+            let contents = create_edit_file(preview, "Some text");
+            assert_eq!(
+                contents,
+                "# Write the text to add to the prompt below the scissors line.\n\
+                # Everything above it is ignored. Save an empty file to cancel.\n#\n\
+                # Rendered prompt:\n#\n\
+                # Summarize this:\n#\n# [your text]\n\
+                # ------------------------ >8 ------------------------\n\
+                Some text"
+            );
+            assert_eq!(parse_edit_file(&contents, preview), "Some text");
+        }
+
+        #[test]
+        fn empty_content() {
+            let contents = create_edit_file("A prompt", "");
+            assert_eq!(parse_edit_file(&contents, "A prompt"), "");
+        }
+
+        #[test]
+        fn keeps_comments_in_content() {
+            let contents = create_edit_file("A prompt", "# A heading\n\nText\n");
+            assert_eq!(
+                parse_edit_file(&contents, "A prompt"),
+                "# A heading\n\nText"
+            );
+        }
+
+        #[test]
+        fn scissors_text_in_preview() {
+            let preview = "Before\n------------------------ >8 ------------------------\nAfter";
+            let contents = create_edit_file(preview, "Some text");
+            assert_eq!(parse_edit_file(&contents, preview), "Some text");
+        }
+
+        #[test]
+        fn scissors_must_be_whole_line() {
+            let contents = create_edit_file("A prompt", &format!("See {EDIT_SCISSORS} here"));
+            assert_eq!(
+                parse_edit_file(&contents, "A prompt"),
+                format!("See {EDIT_SCISSORS} here")
+            );
+        }
+
+        #[test]
+        fn missing_scissors() {
+            assert_eq!(
+                parse_edit_file("Just some text\n", "A prompt"),
+                "Just some text"
+            );
+
+            let preview = "Summarize this:\n\n[your text]";
+            let contents = create_edit_file(preview, "Some text");
+            let contents = contents.replace(EDIT_SCISSORS, "");
+            assert_eq!(parse_edit_file(&contents, preview), "Some text");
+
+            let contents = create_edit_file("A prompt", "").replace(EDIT_SCISSORS, "");
+            assert_eq!(parse_edit_file(&contents, "A prompt"), "");
+        }
+
+        #[test]
+        fn missing_scissors_keeps_headings() {
+            let preview = "# Task\n\n[your text]";
+            let contents = create_edit_file(preview, "# Notes\n\nSome text\n")
+                .replace(&format!("{EDIT_SCISSORS}\n"), "");
+            assert_eq!(parse_edit_file(&contents, preview), "# Notes\n\nSome text");
+        }
     }
 
     mod template_references_extra {
@@ -0,0 +1,575 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::{
+    args::TestArgs,
+    config::Config,
+    context::count_tokens,
+    error::{error_message, Error},
+    prepare_template,
+    vars::TemplateVars,
+    GeneratedTemplate, PreparedTemplate,
+};
+
+/// A test case defined in a template's `[[tests]]` section.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct TemplateTest {
+    /// A name for the test, shown in the report
+    pub name: Option<String>,
+    /// Values for the template's options. Relative paths are resolved from the template's directory.
+    #[serde(default)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+    /// Extra content to add to the prompt
+    pub extra: Option<String>,
+    /// Assertions on the rendered prompt
+    #[serde(default)]
+    pub prompt: Assertions,
+    /// Assertions on the model output. When present, the prompt is sent to the model.
+    pub output: Option<Assertions>,
+}
+
+impl TemplateTest {
+    /// The name of the test, or a name based on its position in the template if it has none.
+    pub fn display_name(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("test {}", index + 1))
+    }
+
+    /// Generate the prompt for this test. Stdin is never read, and relative paths in the option
+    /// values are resolved from `template_dir`.
+    pub fn generate(
+        &self,
+        base_dir: PathBuf,
+        template_name: &str,
+        template_dir: PathBuf,
+    ) -> Result<GeneratedTemplate, Report<Error>> {
+        self.prepare(base_dir, template_name, template_dir)?
+            .into_generated()
+    }
+
+    /// Render the template for this test, without trimming the prompt to fit the context.
+    fn prepare(
+        &self,
+        base_dir: PathBuf,
+        template_name: &str,
+        template_dir: PathBuf,
+    ) -> Result<PreparedTemplate, Report<Error>> {
+        let vars = TemplateVars::new(
+            serde_json::Value::Object(self.options.clone()),
+            template_dir,
+        )?;
+
+        let mut cmdline = ["promptbox", "run", template_name, "--no-stdin"]
+            .into_iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        if let Some(extra) = self.extra.as_ref() {
+            cmdline.push(OsString::from("--"));
+            cmdline.push(OsString::from(extra));
+        }
+
+        prepare_template(base_dir, template_name.to_string(), cmdline, Some(vars))
+    }
+}
+
+/// Checks to run against some text.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Assertions {
+    /// Strings that must appear in the text
+    #[serde(default)]
+    pub contains: Vec<String>,
+    /// Strings that must not appear in the text
+    #[serde(default)]
+    pub not_contains: Vec<String>,
+    /// Regular expressions that must match the text
+    #[serde(default)]
+    pub regex: Vec<String>,
+    /// The text must contain at most this many tokens
+    pub max_tokens: Option<usize>,
+    /// The text must be valid JSON
+    #[serde(default)]
+    pub json: bool,
+    /// The value at each JSON path must equal the given value. Paths look like `$.items[0].name`.
+    #[serde(default)]
+    pub json_path: HashMap<String, serde_json::Value>,
+}
+
+impl Assertions {
+    /// Check the text, returning a message for each failed assertion. Tokens are counted with
+    /// the tokenizer named `tokenizer`.
+    fn check(&self, text: &str, tokenizer: &str) -> Vec<String> {
+        let mut failures = vec![];
+
+        for expected in &self.contains {
+            if !text.contains(expected.as_str()) {
+                failures.push(format!("Expected to contain {expected:?}"));
+            }
+        }
+
+        for unexpected in &self.not_contains {
+            if text.contains(unexpected.as_str()) {
+                failures.push(format!("Expected not to contain {unexpected:?}"));
+            }
+        }
+
+        for pattern in &self.regex {
+            match regex::Regex::new(pattern) {
+                Ok(re) if re.is_match(text) => {}
+                Ok(_) => failures.push(format!("Expected to match regex {pattern:?}")),
+                Err(e) => failures.push(format!("Invalid regex {pattern:?}: {e}")),
+            }
+        }
+
+        if let Some(max_tokens) = self.max_tokens {
+            match count_tokens(text, tokenizer) {
+                Ok(count) if count <= max_tokens => {}
+                Ok(count) => failures.push(format!(
+                    "Expected at most {max_tokens} tokens, but found {count}"
+                )),
+                Err(e) => failures.push(format!("Counting tokens: {}", error_message(&e))),
+            }
+        }
+
+        if self.json || !self.json_path.is_empty() {
+            match serde_json::from_str::<serde_json::Value>(text.trim()) {
+                Ok(value) => {
+                    for (path, expected) in self.json_path.iter().sorted_by_key(|(path, _)| *path) {
+                        match json_path_lookup(&value, path) {
+                            Some(found) if found == expected => {}
+                            Some(found) => failures.push(format!(
+                                "Expected {path} to equal {expected}, but found {found}"
+                            )),
+                            None => failures.push(format!("Expected {path} to exist")),
+                        }
+                    }
+                }
+                Err(e) => failures.push(format!("Expected valid JSON: {e}")),
+            }
+        }
+
+        failures
+    }
+}
+
+/// Look up a value using a simple JSON path, such as `$.items[0].name` or `items.0.name`.
+fn json_path_lookup<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.split(['.', '[', ']'])
+        .filter(|segment| !segment.is_empty())
+        .try_fold(value, |current, segment| match current {
+            serde_json::Value::Object(map) => map.get(segment),
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub template: String,
+    pub name: String,
+    pub failures: Vec<String>,
+    pub duration: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A prompt that was rendered for a test, waiting to be sent to the model.
+struct OutputJob<'a> {
+    result_index: usize,
+    assertions: &'a Assertions,
+    prepared: PreparedTemplate,
+    /// The prompt, trimmed for the first model
+    prompt: String,
+}
+
+/// Run the tests for the templates given in `args`, and return the results.
+pub fn collect_test_results(
+    base_dir: PathBuf,
+    args: &TestArgs,
+) -> Result<Vec<TestResult>, Report<Error>> {
+    let config = Config::from_directory(base_dir.clone())?;
+    let template_names = if args.templates.is_empty() {
+        config.list_templates()
+    } else {
+        args.templates.clone()
+    };
+
+    let mut tests = vec![];
+    let mut results = vec![];
+    for template_name in template_names {
+        match config.find_template(&template_name) {
+            Ok(template) => {
+                let template_dir = template
+                    .definition_path
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                for (i, test) in template.input.tests.into_iter().enumerate() {
+                    tests.push((template_name.clone(), template_dir.clone(), i, test));
+                }
+            }
+            Err(e) => results.push(TestResult {
+                template: template_name,
+                name: "load template".to_string(),
+                failures: vec![error_message(&e)],
+                duration: Duration::ZERO,
+            }),
+        }
+    }
+
+    // Render all the prompts first, since this is fast, and then send the tests that check the
+    // output to the model in parallel.
+    let mut output_jobs = vec![];
+    for (template_name, template_dir, i, test) in &tests {
+        let start = Instant::now();
+        let name = test.display_name(*i);
+        let prepared = test
+            .prepare(base_dir.clone(), template_name, template_dir.clone())
+            .and_then(|prepared| {
+                let prompt = prepared.fit_prompt(&prepared.model_options)?;
+                Ok((prepared, prompt))
+            });
+
+        let failures = match prepared {
+            Ok((prepared, prompt)) => {
+                let failures = test
+                    .prompt
+                    .check(&prompt, prepared.model_options.tokenizer_name());
+                match test.output.as_ref() {
+                    Some(assertions) if failures.is_empty() && !args.skip_output => {
+                        output_jobs.push(OutputJob {
+                            result_index: results.len(),
+                            assertions,
+                            prepared,
+                            prompt,
+                        });
+                    }
+                    _ => {}
+                }
+                failures
+            }
+            Err(e) => vec![format!("Generating prompt: {}", error_message(&e))],
+        };
+
+        results.push(TestResult {
+            template: template_name.clone(),
+            name,
+            failures,
+            duration: start.elapsed(),
+        });
+    }
+
+    let output_results = run_output_jobs(output_jobs, args.jobs.max(1));
+    for (result_index, failures, duration) in output_results {
+        let result = &mut results[result_index];
+        result.failures.extend(failures);
+        result.duration += duration;
+    }
+
+    Ok(results)
+}
+
+/// Send the prompts to the model using `jobs` threads, and check the output of each.
+fn run_output_jobs(
+    output_jobs: Vec<OutputJob>,
+    jobs: usize,
+) -> Vec<(usize, Vec<String>, Duration)> {
+    if output_jobs.is_empty() {
+        return vec![];
+    }
+
+    let (job_tx, job_rx) = flume::unbounded();
+    let (result_tx, result_rx) = flume::unbounded();
+    let num_workers = jobs.min(output_jobs.len());
+    for job in output_jobs {
+        job_tx.send(job).ok();
+    }
+    drop(job_tx);
+
+    std::thread::scope(|scope| {
+        for _ in 0..num_workers {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            scope.spawn(move || {
+                for job in job_rx {
+                    let start = Instant::now();
+                    let failures = match send_prompt(&job.prepared, job.prompt) {
+                        Ok(output) => job
+                            .assertions
+                            .check(&output, job.prepared.model_options.tokenizer_name()),
+                        Err(e) => vec![format!("Running prompt: {}", error_message(&e))],
+                    };
+                    result_tx
+                        .send((job.result_index, failures, start.elapsed()))
+                        .ok();
+                }
+            });
+        }
+    });
+    drop(result_tx);
+
+    result_rx.into_iter().collect()
+}
+
+/// Send a prompt to the model, trying the fallback models if it fails, and return the full
+/// response.
+fn send_prompt(prepared: &PreparedTemplate, prompt: String) -> Result<String, Report<Error>> {
+    let (message_tx, message_rx) = flume::unbounded();
+    prepared.send(Some(prompt), &message_tx)?;
+    drop(message_tx);
+
+    Ok(message_rx.drain().collect())
+}
+
+/// Run the tests, print a report, and return an error if any tests failed.
+pub fn run_tests(base_dir: PathBuf, args: TestArgs) -> Result<(), Report<Error>> {
+    let results = collect_test_results(base_dir, &args)?;
+
+    if results.is_empty() {
+        println!("No tests found");
+        return Ok(());
+    }
+
+    for result in &results {
+        let status = if result.passed() { "ok  " } else { "FAIL" };
+        println!(
+            "{status} {} / {} ({:.2}s)",
+            result.template,
+            result.name,
+            result.duration.as_secs_f64()
+        );
+        for failure in &result.failures {
+            println!("       {failure}");
+        }
+    }
+
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    println!("\n{} passed, {failed} failed", results.len() - failed);
+
+    if let Some(junit_path) = args.junit.as_ref() {
+        std::fs::write(junit_path, junit_report(&results))
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| format!("Writing {}", junit_path.display()))?;
+    }
+
+    if failed > 0 {
+        return Err(Report::new(Error::TestFailure))
+            .attach_printable(format!("{failed} of {} tests failed", results.len()));
+    }
+
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Create a JUnit XML report, with a test suite for each template.
+fn junit_report(results: &[TestResult]) -> String {
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"promptbox\" tests=\"{}\" failures=\"{failed}\">\n",
+        results.len()
+    ));
+
+    for (template, suite) in &results.iter().group_by(|r| r.template.as_str()) {
+        let suite = suite.collect::<Vec<_>>();
+        let suite_failed = suite.iter().filter(|r| !r.passed()).count();
+        let suite_time: Duration = suite.iter().map(|r| r.duration).sum();
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{suite_failed}\" time=\"{:.3}\">\n",
+            xml_escape(template),
+            suite.len(),
+            suite_time.as_secs_f64()
+        ));
+
+        for result in suite {
+            xml.push_str(&format!(
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                xml_escape(&result.name),
+                xml_escape(template),
+                result.duration.as_secs_f64()
+            ));
+
+            if result.passed() {
+                xml.push_str(" />\n");
+            } else {
+                let message = result.failures.join("\n");
+                xml.push_str(&format!(
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    xml_escape(&result.failures[0]),
+                    xml_escape(&message)
+                ));
+            }
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{context::DEFAULT_TOKENIZER, tests::base_dir};
+
+    #[test]
+    fn text_assertions() {
+        let assertions = Assertions {
+            contains: vec!["hello".to_string()],
+            not_contains: vec!["goodbye".to_string()],
+            regex: vec![r"\d+ apples".to_string()],
+            ..Default::default()
+        };
+
+        assert!(assertions
+            .check("hello, I have 5 apples", DEFAULT_TOKENIZER)
+            .is_empty());
+        assert_eq!(
+            assertions
+                .check("goodbye, I have some apples", DEFAULT_TOKENIZER)
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn json_assertions() {
+        let assertions = Assertions {
+            json_path: [
+                ("$.items[1].name".to_string(), serde_json::json!("b")),
+                ("count".to_string(), serde_json::json!(2)),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+
+        assert!(assertions
+            .check(
+                r#"{ "count": 2, "items": [{ "name": "a" }, { "name": "b" }] }"#,
+                DEFAULT_TOKENIZER
+            )
+            .is_empty());
+        assert_eq!(
+            assertions.check(r#"{ "count": 3, "items": [] }"#, DEFAULT_TOKENIZER),
+            vec![
+                "Expected $.items[1].name to exist".to_string(),
+                "Expected count to equal 2, but found 3".to_string()
+            ]
+        );
+        assert_eq!(assertions.check("not json", DEFAULT_TOKENIZER).len(), 1);
+    }
+
+    #[test]
+    fn run_template_tests() {
+        let args = TestArgs {
+            templates: vec!["template_tests".to_string()],
+            ..Default::default()
+        };
+
+        let results = collect_test_results(base_dir(""), &args).expect("running tests");
+        let summary = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.failures.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("passes", 0),
+                ("fails", 2),
+                ("test 3", 0),
+                ("bad option", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn output_assertions() {
+        let args = TestArgs {
+            templates: vec!["mock".to_string()],
+            jobs: 2,
+            ..Default::default()
+        };
+
+        let results = collect_test_results(base_dir(""), &args).expect("running tests");
+        let summary = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.failures.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![("summary", 0), ("not json", 1)]);
+    }
+
+    #[test]
+    fn output_with_fallback() {
+        let args = TestArgs {
+            templates: vec!["fallback".to_string()],
+            ..Default::default()
+        };
+
+        let results = collect_test_results(base_dir(""), &args).expect("running tests");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].passed(), "{:?}", results[0].failures);
+    }
+
+    #[test]
+    fn max_tokens_with_configured_tokenizer() {
+        let args = TestArgs {
+            templates: vec!["tokenizer_tests".to_string()],
+            ..Default::default()
+        };
+
+        // The model's tokenizer doesn't exist, so the count fails instead of using the default.
+        let results = collect_test_results(base_dir(""), &args).expect("running tests");
+        assert_eq!(results.len(), 1);
+        let failures = &results[0].failures;
+        assert_eq!(failures.len(), 1, "{failures:?}");
+        assert!(
+            failures[0].contains("Loading tokenizer promptbox-test/missing-tokenizer"),
+            "{failures:?}"
+        );
+    }
+
+    #[test]
+    fn junit() {
+        let results = vec![
+            TestResult {
+                template: "a".to_string(),
+                name: "first".to_string(),
+                failures: vec![],
+                duration: Duration::from_millis(500),
+            },
+            TestResult {
+                template: "a".to_string(),
+                name: "second".to_string(),
+                failures: vec!["Expected to contain \"<x>\"".to_string()],
+                duration: Duration::ZERO,
+            },
+        ];
+
+        let report = junit_report(&results);
+        assert!(report.contains(r#"<testsuite name="a" tests="2" failures="1" time="0.500">"#));
+        assert!(report.contains(r#"<testcase name="first" classname="a" time="0.500" />"#));
+        assert!(report.contains(r#"<failure message="Expected to contain &quot;&lt;x&gt;&quot;">"#));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use clap::ArgMatches;
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    error::Error,
+    template::{OptionType, PromptTemplate},
+};
+
+/// Values for a template's options, read from a file or from stdin.
+#[derive(Debug)]
+pub struct TemplateVars {
+    values: serde_json::Map<String, serde_json::Value>,
+    /// Relative file and image paths are resolved from this directory.
+    base_dir: PathBuf,
+}
+
+impl TemplateVars {
+    /// Load the values specified by the `--vars` or `--vars-stdin` arguments, if present.
+    pub fn from_args(args: &ArgMatches, base_dir: &Path) -> Result<Option<Self>, Report<Error>> {
+        if let Some(path) = args.get_one::<PathBuf>("vars") {
+            Self::from_file(&base_dir.join(path)).map(Some)
+        } else if args.get_flag("vars_stdin") {
+            let contents = std::io::read_to_string(std::io::stdin())
+                .change_context(Error::Io)
+                .attach_printable("Reading vars from stdin")?;
+            let values = serde_json::from_str(&contents)
+                .change_context(Error::ArgParseFailure)
+                .attach_printable("Parsing vars from stdin")?;
+            Self::new(values, base_dir.to_path_buf()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read values from a JSON or TOML file. The format is chosen based on the file extension.
+    pub fn from_file(path: &Path) -> Result<Self, Report<Error>> {
+        let contents = std::fs::read_to_string(path)
+            .change_context(Error::Io)
+            .attach_printable_lazy(|| format!("Reading vars file {}", path.display()))?;
+
+        let values = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&contents).change_context(Error::ArgParseFailure)
+        } else {
+            serde_json::from_str(&contents).change_context(Error::ArgParseFailure)
+        }
+        .attach_printable_lazy(|| path.display().to_string())?;
+
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Self::new(values, base_dir).attach_printable_lazy(|| path.display().to_string())
+    }
+
+    pub fn new(values: serde_json::Value, base_dir: PathBuf) -> Result<Self, Report<Error>> {
+        let serde_json::Value::Object(values) = values else {
+            return Err(Report::new(Error::ArgParseFailure))
+                .attach_printable("Vars must be an object of option names and values");
+        };
+
+        Ok(Self { values, base_dir })
+    }
+
+    /// Convert the values into strings which can be parsed by the template's arguments. Values
+    /// that don't match any option in the template are ignored.
+    pub fn preset_values(
+        &self,
+        template: &PromptTemplate,
+    ) -> Result<HashMap<String, Vec<String>>, Report<Error>> {
+        let mut presets = HashMap::new();
+        for (name, option) in &template.options {
+            let Some(value) = self.values.get(name) else {
+                continue;
+            };
+
+            let values = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::Array(values) if option.array => values.iter().collect(),
+                serde_json::Value::Array(_) => {
+                    return Err(Report::new(Error::ArgParseFailure))
+                        .attach_printable(format!("Option {name} does not accept an array"));
+                }
+                value => vec![value],
+            };
+
+            let values = values
+                .into_iter()
+                .map(|value| self.value_to_arg(option.option_type, value))
+                .collect::<Result<Vec<_>, _>>()
+                .attach_printable_lazy(|| format!("Option {name}"))?;
+            presets.insert(name.clone(), values);
+        }
+
+        Ok(presets)
+    }
+
+    fn value_to_arg(
+        &self,
+        option_type: OptionType,
+        value: &serde_json::Value,
+    ) -> Result<String, Report<Error>> {
+        let value = match value {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::Bool(b) => b.to_string(),
+            _ => {
+                return Err(Report::new(Error::ArgParseFailure))
+                    .attach_printable(format!("Unsupported value {value}"));
+            }
+        };
+
+        if option_type.is_path() {
+            Ok(self.base_dir.join(value).to_string_lossy().into_owned())
+        } else {
+            Ok(value)
+        }
+    }
+}
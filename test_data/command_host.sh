@@ -0,0 +1,4 @@
+#!/bin/sh
+# A command host for tests, which answers every request the same way.
+cat > /dev/null
+printf '{"delta":"from a "}\n{"delta":"command"}\n'